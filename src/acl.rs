@@ -0,0 +1,180 @@
+use std::{
+    fs,
+    sync::OnceLock
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    // Anonymous access
+    Allow,
+    Deny,
+    // Require an authenticated user (whose own permissions still apply)
+    Auth
+}
+
+struct Rule {
+    pattern: String,
+    // Empty means any method
+    methods: Vec<String>,
+    action: Action
+}
+
+impl Rule {
+    fn matches(&self, path: &str, method: &str) -> bool {
+        let method_matches = self.methods.is_empty() || self.methods.iter().any(|m| m == method || (m == "GET" && method == "HEAD"));
+        method_matches && glob_match(&self.pattern, path)
+    }
+}
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+// `*` matches within a path segment, `**` across segments and `?` a single character.
+// A pattern ending in `/**` also matches the directory itself.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix("/**") {
+        if glob_match(prefix, path) || glob_match(prefix, path.strip_suffix('/').unwrap_or(path)) {
+            return true;
+        }
+    }
+    match_bytes(pattern.as_bytes(), path.as_bytes())
+}
+
+enum Token {
+    // `**`
+    AnyPath,
+    // `*`
+    AnyInSegment,
+    // `?`
+    AnyChar,
+    Byte(u8)
+}
+
+// Works back to front over the pattern, keeping for each path position whether the rest of the
+// pattern matches the rest of the path. That takes pattern length times path length steps, where
+// trying every split for each wildcard could take exponentially many.
+fn match_bytes(pattern: &[u8], path: &[u8]) -> bool {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        let token = match pattern[i] {
+            b'*' if pattern.get(i + 1) == Some(&b'*') => {
+                i += 1;
+                Token::AnyPath
+            }
+            b'*' => Token::AnyInSegment,
+            b'?' => Token::AnyChar,
+            c => Token::Byte(c)
+        };
+        tokens.push(token);
+        i += 1;
+    }
+    // An empty pattern only matches the end of the path
+    let mut matched = vec![false; path.len() + 1];
+    matched[path.len()] = true;
+    for token in tokens.iter().rev() {
+        let mut current = vec![false; path.len() + 1];
+        for i in (0..=path.len()).rev() {
+            let next = path.get(i).copied();
+            current[i] = match token {
+                Token::AnyPath => matched[i] || (next.is_some() && current[i + 1]),
+                Token::AnyInSegment => matched[i] || (matches!(next, Some(c) if c != b'/') && current[i + 1]),
+                Token::AnyChar => matches!(next, Some(c) if c != b'/') && matched[i + 1],
+                Token::Byte(byte) => next == Some(*byte) && matched[i + 1]
+            };
+        }
+        matched = current;
+    }
+    matched[0]
+}
+
+// One rule per line: `<glob> <methods> <allow|deny|auth>`, where methods is a comma separated list or `*`.
+// The first matching rule wins; GET also covers HEAD.
+pub fn load(path: &str) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read ACL file \"{}\": {}", path, e))?;
+    let mut rules = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; };
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let action = match fields.get(2).map(|a| a.to_lowercase()).as_deref() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            Some("auth") => Action::Auth,
+            _ => return Err(format!("Invalid rule on line {} of ACL file \"{}\"", i + 1, path))
+        };
+        if fields.len() != 3 || !fields[0].starts_with('/') {
+            return Err(format!("Invalid rule on line {} of ACL file \"{}\"", i + 1, path));
+        }
+        let methods = if fields[1] == "*" {
+            Vec::new()
+        } else {
+            fields[1].split(',').map(|m| m.trim().to_uppercase()).filter(|m| !m.is_empty()).collect()
+        };
+        rules.push(Rule {
+            pattern: fields[0].to_string(),
+            methods,
+            action
+        });
+    }
+    RULES.set(rules).map_err(|_| String::from("ACL file has already been loaded"))
+}
+
+pub fn deny_all() {
+    let _ = RULES.set(vec![Rule {
+        pattern: String::from("/**"),
+        methods: Vec::new(),
+        action: Action::Deny
+    }]);
+}
+
+// None when no rule matches (or no ACL is configured)
+pub fn evaluate(path: &str, method: &str) -> Option<Action> {
+    RULES.get()?.iter().find(|rule| rule.matches(path, method)).map(|rule| rule.action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_star_stays_in_segment() {
+        assert!(glob_match("/files/*.txt", "/files/a.txt"));
+        assert!(glob_match("/files/*", "/files/"));
+        assert!(!glob_match("/files/*.txt", "/files/sub/a.txt"));
+        assert!(!glob_match("/files/*", "/files"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(glob_match("/files/**.txt", "/files/sub/dir/a.txt"));
+        assert!(glob_match("/**", "/"));
+        assert!(glob_match("/a/**/b", "/a/x/y/b"));
+        assert!(!glob_match("/a/**/b", "/a/b"));
+        assert!(glob_match("/***", "/a/b"));
+    }
+
+    #[test]
+    fn trailing_double_star_matches_directory() {
+        assert!(glob_match("/private/**", "/private"));
+        assert!(glob_match("/private/**", "/private/"));
+        assert!(glob_match("/private/**", "/private/a/b"));
+        assert!(!glob_match("/private/**", "/privateer"));
+    }
+
+    #[test]
+    fn question_mark_is_one_character() {
+        assert!(glob_match("/v?/api", "/v1/api"));
+        assert!(!glob_match("/v?/api", "/v10/api"));
+        assert!(!glob_match("/a?b", "/a/b"));
+        assert!(!glob_match("/a?", "/a"));
+    }
+
+    #[test]
+    fn many_wildcards_on_a_long_path_are_fast() {
+        let path = format!("/{}", "a".repeat(20000));
+        let start = std::time::Instant::now();
+        assert!(!glob_match("/**a**b**c**d", &path));
+        assert!(!glob_match("/*a*b*c*d", &path));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH}
+};
+use openssl::{
+    hash::{hash, MessageDigest},
+    memcmp
+};
+use server::{relative_path, url_decode};
+use crate::session::{sign, random_hex, to_hex};
+
+pub static REALM: &str = "SimpleWebServer";
+const NONCE_LIFETIME: u64 = 300;
+
+pub enum DigestResult {
+    Valid(String),
+    // Correct credentials with an expired nonce; the client should retry without prompting the user
+    Stale,
+    Invalid
+}
+
+// Highest nonce count seen for each live nonce, to reject replayed requests
+static NONCE_COUNTS: OnceLock<Mutex<HashMap<String, (u64, u64)>>> = OnceLock::new();
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn digest_hex(algorithm: &str, data: &str) -> String {
+    let md = if algorithm.starts_with("SHA-256") { MessageDigest::sha256() } else { MessageDigest::md5() };
+    hash(md, data.as_bytes()).map(|d| to_hex(&d)).unwrap_or_default()
+}
+
+pub fn create_nonce(secret_key: &str) -> String {
+    let payload = format!("{:x}.{}", now(), random_hex(8));
+    let signature = sign(&payload, secret_key);
+    format!("{}.{}", payload, &signature[..signature.len().min(32)])
+}
+
+// Returns the issue time of a nonce this server signed
+fn check_nonce(nonce: &str, secret_key: &str) -> Option<u64> {
+    let (payload, signature) = nonce.rsplit_once('.')?;
+    let expected = sign(payload, secret_key);
+    let expected = &expected[..expected.len().min(32)];
+    if expected.len() != signature.len() || !memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+        return None;
+    }
+    u64::from_str_radix(payload.split('.').next()?, 16).ok()
+}
+
+pub fn challenges(secret_key: &str, stale: bool) -> Vec<String> {
+    let nonce = create_nonce(secret_key);
+    ["SHA-256", "MD5"].iter().map(|algorithm| {
+        format!("Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}", REALM, algorithm, nonce, if stale { ", stale=true" } else { "" })
+    }).collect()
+}
+
+// Parses the comma separated `key=value` / `key="quoted value"` list after the scheme name
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect::<String>().trim().to_lowercase();
+        if key.is_empty() { break; };
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => { if let Some(escaped) = chars.next() { value.push(escaped); } }
+                    '"' => break,
+                    _ => value.push(c)
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' { break; };
+                value.push(*c);
+                chars.next();
+            }
+        }
+        params.insert(key, value.trim().to_string());
+    }
+    params
+}
+
+fn username(params: &HashMap<String, String>) -> Option<String> {
+    if let Some(name) = params.get("username") {
+        return Some(name.clone());
+    }
+    // RFC 8187 encoding, e.g. username*=UTF-8''J%C3%A4s%C3%B8n
+    let encoded = params.get("username*")?;
+    let (charset, rest) = encoded.split_once('\'')?;
+    let (_, value) = rest.split_once('\'')?;
+    if !charset.eq_ignore_ascii_case("utf-8") { return None; };
+    Some(url_decode(value))
+}
+
+// Validates the credentials without recording the nonce count
+fn check<F>(header: &str, method: &str, path: &str, secret_key: &str, lookup_password: F) -> (DigestResult, String, u64, u64)
+where
+    F: Fn(&str) -> Option<String>
+{
+    let invalid = (DigestResult::Invalid, String::new(), 0, 0);
+    if !header.to_lowercase().starts_with("digest ") { return invalid; };
+    let params = parse_params(&header[7..]);
+    let get = |key: &str| params.get(key).map(|v| v.as_str()).unwrap_or("");
+    let algorithm = if get("algorithm").is_empty() { "MD5" } else { get("algorithm") };
+    if !["MD5", "MD5-sess", "SHA-256", "SHA-256-sess"].contains(&algorithm) || get("qop") != "auth" || get("realm") != REALM {
+        return invalid;
+    }
+    let Some(username) = username(&params) else { return invalid; };
+    let (nonce, uri, nc, cnonce) = (get("nonce"), get("uri"), get("nc"), get("cnonce"));
+    let Some(issued) = check_nonce(nonce, secret_key) else { return invalid; };
+    let Ok(count) = u64::from_str_radix(nc, 16) else { return invalid; };
+
+    // The digest covers the request target; make sure it is the resource actually being requested
+    let uri_path = uri.split('?').next().unwrap_or("");
+    if relative_path("", &url_decode(uri_path)) != path {
+        return invalid;
+    }
+
+    let Some(password) = lookup_password(&username) else { return invalid; };
+    let mut ha1 = digest_hex(algorithm, &format!("{}:{}:{}", username, REALM, password));
+    if algorithm.ends_with("-sess") {
+        ha1 = digest_hex(algorithm, &format!("{}:{}:{}", ha1, nonce, cnonce));
+    }
+    let ha2 = digest_hex(algorithm, &format!("{}:{}", method, uri));
+    let expected = digest_hex(algorithm, &format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+    let response = get("response").to_lowercase();
+    if expected.is_empty() || expected.len() != response.len() || !memcmp::eq(expected.as_bytes(), response.as_bytes()) {
+        return invalid;
+    }
+
+    if now().saturating_sub(issued) > NONCE_LIFETIME {
+        return (DigestResult::Stale, nonce.to_string(), count, issued);
+    }
+    (DigestResult::Valid(username), nonce.to_string(), count, issued)
+}
+
+pub fn is_stale<F>(header: &str, method: &str, path: &str, secret_key: &str, lookup_password: F) -> bool
+where
+    F: Fn(&str) -> Option<String>
+{
+    matches!(check(header, method, path, secret_key, lookup_password).0, DigestResult::Stale)
+}
+
+// `lookup_password` returns the password of a user, or None if they don't exist
+pub fn verify<F>(header: &str, method: &str, path: &str, secret_key: &str, lookup_password: F) -> DigestResult
+where
+    F: Fn(&str) -> Option<String>
+{
+    let (result, nonce, count, issued) = check(header, method, path, secret_key, lookup_password);
+    let DigestResult::Valid(_) = result else { return result; };
+    let current = now();
+    let Ok(mut counts) = NONCE_COUNTS.get_or_init(|| Mutex::new(HashMap::new())).lock() else { return DigestResult::Invalid; };
+    counts.retain(|_, (_, issued)| current.saturating_sub(*issued) <= NONCE_LIFETIME);
+    let last = counts.entry(nonce).or_insert((0, issued));
+    if count <= last.0 {
+        return DigestResult::Invalid;
+    }
+    last.0 = count;
+    result
+}
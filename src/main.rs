@@ -1,25 +1,90 @@
 mod simple_web_server;
+mod session;
+mod acl;
+mod users;
+mod tokens;
+mod digest;
+mod rate_limit;
+mod status;
 
 use std::{thread, time::Duration, env};
 use crate::simple_web_server::SimpleWebServer;
-use server::{relative_path, Settings, generate_dummy_cert_and_key};
+use server::{relative_path, Settings, systemd, Listener, generate_dummy_cert_and_key, error::Error, logging::{self, Level}, access_log::AccessLogFormat};
 use clap::Parser;
 
 fn string_to_static_str(s: String) -> &'static str {
     Box::leak(s.into_boxed_str())
 }
 
+// Parses "[https://]host:port", e.g. "0.0.0.0:8080" or "https://[::]:8443"
+fn parse_listener(input: &str) -> Result<Listener<'static>, String> {
+    let (https, rest) = match input.split_once("://") {
+        Some(("https", rest)) => (true, rest),
+        Some(("http", rest)) => (false, rest),
+        Some(_) => return Err(format!("Unknown scheme in listen address \"{}\"", input)),
+        None => (false, input)
+    };
+    let rest = rest.trim_end_matches('/');
+    let Some((host, port)) = rest.rsplit_once(':') else {
+        return Err(format!("Listen address \"{}\" is missing a port", input));
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        return Err(format!("Invalid port in listen address \"{}\"", input));
+    };
+    Ok(Listener {
+        host: string_to_static_str(host.to_string()),
+        port: port as i32,
+        https
+    })
+}
+
+fn strings_to_static_slice(v: Vec<String>) -> &'static [&'static str] {
+    Box::leak(v.into_iter().map(string_to_static_str).collect::<Vec<_>>().into_boxed_slice())
+}
+
+fn start_error_hint(err: &Error) -> Option<&'static str> {
+    match err {
+        Error::Bind { source, .. } => match source.kind() {
+            std::io::ErrorKind::AddrInUse => Some("Another process is using this address. Choose a different --port, use --port 0 for a free one, or pass --port-auto-increment."),
+            std::io::ErrorKind::PermissionDenied => Some("Ports below 1024 usually require elevated privileges. Try a port such as 8080."),
+            std::io::ErrorKind::AddrNotAvailable => Some("The address does not belong to this machine. Check --bind/--listen."),
+            _ => None
+        },
+        Error::Tls(_) => Some("Check that the HTTPS certificate and private key are valid PEM and belong together."),
+        Error::Config(_) => Some("Check the command line options."),
+        _ => None
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(default_value = "./")]
     path: String,
 
-    #[arg(short, long, default_value_t = 8080, help = "Port to listen on")]
+    #[arg(short, long, default_value_t = 8080, help = "Port to listen on (0 picks a free port)")]
     port: i32,
+
+    #[arg(long, value_name = "COUNT", default_value_t = 0, help = "Try up to COUNT following ports when --port is already in use")]
+    port_auto_increment: u16,
     
     #[arg(short, long, default_value_t = false, help = "Listen on local network")]
     network: bool,
+
+    #[arg(long, value_name = "ADDRESS", help = "Bind to this exact address (e.g. 192.168.1.5 or :: for dual-stack)")]
+    bind: Option<String>,
+
+    #[arg(long, value_name = "[https://]HOST:PORT", help = "Listen on this address instead of --bind/--port (repeatable)")]
+    listen: Vec<String>,
+
+    #[arg(long, value_name = "PATH", help = "Listen on a Unix domain socket instead of TCP")]
+    unix_socket: Option<String>,
+
+    #[arg(long, value_name = "OCTAL", default_value = "660", help = "Permissions of the Unix domain socket file")]
+    unix_socket_mode: String,
+
+    #[arg(long, default_value_t = false, help = "Use sockets passed by systemd socket activation (LISTEN_FDS) when present")]
+    systemd: bool,
     
     #[arg(short, long, default_value_t = false, help = "Automatically render index.html")]
     index: bool,
@@ -34,24 +99,139 @@ struct Args {
     delete: bool,
 
     #[arg(long, default_value_t = true, help = "Render directory listing")]
-    dir_listing: bool
+    dir_listing: bool,
+
+    #[arg(long, value_name = "USER:PASSWORD", help = "Require authentication")]
+    auth: Option<String>,
+
+    #[arg(long, value_name = "PATH", help = "Require authentication against an htpasswd-style users file (user:hash[:rights[:paths]])")]
+    users_file: Option<String>,
+
+    #[arg(long, value_name = "PATH", help = "Accept Bearer / X-Api-Key tokens listed in this file (name:token:scopes[:expires])")]
+    tokens_file: Option<String>,
+
+    #[arg(long, value_name = "PATH", help = "Path-scoped access rules, one `<glob> <methods> <allow|deny|auth>` per line")]
+    acl: Option<String>,
+
+    #[arg(long, default_value_t = false, help = "Use a login page and session cookies instead of HTTP Basic auth")]
+    login_page: bool,
+
+    #[arg(long, default_value_t = false, help = "Use HTTP Digest authentication (SHA-256/MD5) instead of Basic")]
+    digest: bool,
+
+    #[arg(long, default_value_t = 86400, help = "Login session lifetime in seconds")]
+    session_timeout: u64,
+
+    #[arg(long, default_value_t = 0, help = "Maximum sustained requests per second per client IP (0 = unlimited)")]
+    rate_limit: u32,
+
+    #[arg(long, default_value_t = 0, help = "Requests a client may burst above the rate limit (0 = same as --rate-limit)")]
+    rate_limit_burst: u32,
+
+    #[arg(long, default_value_t = 10, help = "Failed logins before a client IP is locked out (0 = never)")]
+    auth_max_failures: u32,
+
+    #[arg(long, default_value_t = 300, help = "Auth lockout duration in seconds")]
+    auth_lockout: u64,
+
+    #[arg(long, value_name = "IP/CIDR", help = "Only accept connections from these addresses (repeatable)")]
+    allow: Vec<String>,
+
+    #[arg(long, value_name = "IP/CIDR", help = "Refuse connections from these addresses (repeatable)")]
+    deny: Vec<String>,
+
+    #[arg(long, value_name = "LEVEL", default_value = "info", help = "Log level: error, warn, info or debug")]
+    log_level: Level,
+
+    #[arg(long, value_name = "PATH", help = "Write an access log to this file (- for stdout)")]
+    access_log: Option<String>,
+
+    #[arg(long, value_name = "FORMAT", default_value = "combined", help = "Access log format: common, combined or json")]
+    access_log_format: AccessLogFormat,
+
+    #[arg(long, value_name = "BYTES", default_value_t = 10 * 1024 * 1024, help = "Rotate the access log file at this size (0 = never)")]
+    access_log_max_size: u64,
+
+    #[arg(long, value_name = "COUNT", default_value_t = 5, help = "Rotated access log files to keep")]
+    access_log_max_files: u32,
+
+    #[arg(long, default_value_t = false, help = "Expose Prometheus metrics")]
+    metrics: bool,
+
+    #[arg(long, value_name = "PATH", default_value = "/metrics", help = "Path of the metrics endpoint")]
+    metrics_path: String,
+
+    #[arg(long, value_name = "PORT", default_value_t = 0, help = "Serve metrics on this separate port only (0 = on the regular port)")]
+    metrics_port: i32,
+
+    #[arg(long, default_value_t = false, help = "Answer /healthz with 200 ok (no authentication)")]
+    healthz: bool,
+
+    #[arg(long, default_value_t = false, help = "Serve a status page at /status (?format=json for JSON)")]
+    status: bool,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = 30, help = "Ping idle WebSocket clients this often (0 = never)")]
+    ws_ping_interval: u64,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = 120, help = "Close WebSocket connections idle this long (0 = never)")]
+    ws_idle_timeout: u64,
+
+    #[arg(long, default_value_t = false, help = "Compress WebSocket messages (permessage-deflate) for clients that support it")]
+    ws_compression: bool,
+
+    #[arg(long, default_value_t = false, help = "Reset the WebSocket compressor after every message (less memory, larger messages)")]
+    ws_no_context_takeover: bool,
+
+    #[arg(long, value_name = "NAME", help = "WebSocket subprotocol to accept (repeatable)")]
+    ws_protocol: Vec<String>,
+
+    #[arg(long, value_name = "ORIGIN", help = "Only accept WebSockets from pages on this origin, e.g. https://example.com (repeatable)")]
+    ws_origin: Vec<String>,
+
+    #[arg(long, value_name = "PATH", help = "Serve a WebSocket echo endpoint at this path")]
+    ws_echo: Option<String>,
+
+    #[arg(long, value_name = "PATH", help = "Serve a WebSocket pub-sub endpoint at this path (join channels with ?channel=NAME)")]
+    ws_broadcast: Option<String>,
+
+    #[arg(long, default_value_t = false, help = "Reload browsers when files in the served directory change")]
+    live_reload: bool
 }
 
 
 fn main() {
     let args = Args::parse();
+    // Before anything spawns a thread, since this edits the environment
+    let systemd_listen_fds = if args.systemd { systemd::take_listen_fds() } else { Vec::new() };
+    logging::set_level(args.log_level);
+
+    let Ok(unix_socket_mode) = u32::from_str_radix(&args.unix_socket_mode, 8) else {
+        eprintln!("Invalid --unix-socket-mode \"{}\", expected an octal mode such as 660", args.unix_socket_mode);
+        std::process::exit(2);
+    };
+
+    let mut listeners = Vec::new();
+    for listen in args.listen.iter() {
+        match parse_listener(listen) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(2);
+            }
+        }
+    }
 
     let mut cert = String::new();
     let mut key = String::new();
     
-    if args.https {
+    if args.https || listeners.iter().any(|listener| listener.https) {
         match generate_dummy_cert_and_key() {
             Ok((certt, keyy)) => {
                 cert = certt;
                 key = keyy;
             }
             Err(err) => {
-                eprintln!("Error generating certificate and key: {:?}", err);
+                logging::error(format!("Error generating certificate and key: {:?}", err));
             }
         }
     }
@@ -64,11 +244,22 @@ fn main() {
     let current_path = env::current_dir().unwrap_or(default_path.into()).into_os_string().into_string().unwrap_or(String::from(default_path)).replace("\\", "/");
     let path = if args.path.starts_with("/") || args.path.contains(":") { args.path } else { relative_path(&current_path, &args.path) };
 
+    let (http_auth_username, http_auth_password) = match &args.auth {
+        Some(auth) => auth.split_once(':').unwrap_or((auth, "")),
+        None => ("", "")
+    };
+
     let settings = Settings {
         path: string_to_static_str(path),
+        bind: string_to_static_str(args.bind.clone().unwrap_or_default()),
+        listeners: Box::leak(listeners.into_boxed_slice()),
+        unix_socket: string_to_static_str(args.unix_socket.clone().unwrap_or_default()),
+        unix_socket_mode,
+        systemd_listen_fds: Box::leak(systemd_listen_fds.into_boxed_slice()),
         index: args.index,
         local_network: args.network,
         port: args.port,
+        port_auto_increment: args.port_auto_increment,
         spa: false,
         rewrite_to: "",
         directory_listing: args.dir_listing,
@@ -84,15 +275,55 @@ fn main() {
         custom404: "",
         custom403: "",
         custom401: "",
-        http_auth: false,
-        http_auth_username: "く",
-        http_auth_password: "password",
+        http_auth: args.auth.is_some() || args.users_file.is_some() || args.tokens_file.is_some(),
+        http_auth_username: string_to_static_str(http_auth_username.to_string()),
+        http_auth_password: string_to_static_str(http_auth_password.to_string()),
+        http_auth_users_file: string_to_static_str(args.users_file.clone().unwrap_or_default()),
+        http_auth_tokens_file: string_to_static_str(args.tokens_file.clone().unwrap_or_default()),
+        http_auth_login_page: args.login_page,
+        http_auth_digest: args.digest,
+        acl_file: string_to_static_str(args.acl.clone().unwrap_or_default()),
+        rate_limit: args.rate_limit,
+        rate_limit_burst: args.rate_limit_burst,
+        auth_max_failures: args.auth_max_failures,
+        auth_lockout: args.auth_lockout,
+        ip_allowlist: strings_to_static_slice(args.allow.clone()),
+        ip_denylist: strings_to_static_slice(args.deny.clone()),
+        session_secret: "",
+        session_timeout: args.session_timeout,
+        access_log: string_to_static_str(args.access_log.clone().unwrap_or_default()),
+        access_log_format: args.access_log_format,
+        access_log_max_size: args.access_log_max_size,
+        access_log_max_files: args.access_log_max_files,
+        metrics_path: if args.metrics { string_to_static_str(args.metrics_path.clone()) } else { "" },
+        metrics_port: args.metrics_port,
+        health_check: args.healthz,
+        status_page: args.status,
+        websocket_ping_interval: args.ws_ping_interval,
+        websocket_idle_timeout: args.ws_idle_timeout,
+        websocket_compression: args.ws_compression,
+        websocket_no_context_takeover: args.ws_no_context_takeover,
+        websocket_protocols: strings_to_static_slice(args.ws_protocol.clone()),
+        websocket_origins: strings_to_static_slice(args.ws_origin.clone()),
+        websocket_echo: string_to_static_str(args.ws_echo.clone().unwrap_or_default()),
+        websocket_broadcast: string_to_static_str(args.ws_broadcast.clone().unwrap_or_default()),
+        live_reload: args.live_reload,
         https: args.https,
         https_cert: string_to_static_str(cert),
         https_key: string_to_static_str(key)
     };
     let mut server = SimpleWebServer::new(settings);
-    println!("Server started: {}", server.start());
+    let addr = match server.start() {
+        Ok(addr) => addr,
+        Err(err) => {
+            logging::error(format!("Server failed to start: {}", err));
+            if let Some(hint) = start_error_hint(&err) {
+                logging::error(hint);
+            }
+            std::process::exit(1);
+        }
+    };
+    logging::info(format!("Server started: {}", addr));
     //let mut i = 0;
     loop {
     //    i += 1;
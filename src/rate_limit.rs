@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant}
+};
+
+// Maps are pruned of idle clients once they grow past this
+const MAX_TRACKED: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant
+}
+
+struct Failures {
+    count: u32,
+    first: Instant,
+    locked_until: Option<Instant>
+}
+
+static BUCKETS: OnceLock<Mutex<HashMap<IpAddr, Bucket>>> = OnceLock::new();
+static FAILURES: OnceLock<Mutex<HashMap<IpAddr, Failures>>> = OnceLock::new();
+
+fn seconds_until(time: Instant, now: Instant) -> u64 {
+    time.saturating_duration_since(now).as_secs_f64().ceil().max(1.0) as u64
+}
+
+// Token bucket refilling `rate` tokens per second up to `burst`. Returns the seconds to wait when empty
+pub fn check_request(ip: IpAddr, rate: u32, burst: u32) -> Result<(), u64> {
+    if rate == 0 { return Ok(()); };
+    let capacity = if burst == 0 { rate } else { burst } as f64;
+    let Ok(mut buckets) = BUCKETS.get_or_init(|| Mutex::new(HashMap::new())).lock() else { return Ok(()); };
+    let now = Instant::now();
+    if buckets.len() > MAX_TRACKED {
+        // A bucket that would have refilled completely is the same as no bucket
+        let refill = Duration::from_secs_f64(capacity / rate as f64);
+        buckets.retain(|_, bucket| now.duration_since(bucket.last) < refill);
+    }
+    let bucket = buckets.entry(ip).or_insert(Bucket {
+        tokens: capacity,
+        last: now
+    });
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate as f64).min(capacity);
+    bucket.last = now;
+    if bucket.tokens < 1.0 {
+        return Err(((1.0 - bucket.tokens) / rate as f64).ceil().max(1.0) as u64);
+    }
+    bucket.tokens -= 1.0;
+    Ok(())
+}
+
+// Seconds left on the lockout of this client, if it is locked out
+pub fn locked_out(ip: IpAddr) -> Option<u64> {
+    let mut failures = FAILURES.get()?.lock().ok()?;
+    let now = Instant::now();
+    let entry = failures.get(&ip)?;
+    match entry.locked_until {
+        Some(until) if until > now => Some(seconds_until(until, now)),
+        Some(_) => {
+            failures.remove(&ip);
+            None
+        }
+        None => None
+    }
+}
+
+// Locks the client out for `lockout` seconds after `max_failures` failures within that same period
+pub fn record_failure(ip: IpAddr, max_failures: u32, lockout: u64) {
+    if max_failures == 0 { return; };
+    let Ok(mut failures) = FAILURES.get_or_init(|| Mutex::new(HashMap::new())).lock() else { return; };
+    let now = Instant::now();
+    let window = Duration::from_secs(lockout);
+    if failures.len() > MAX_TRACKED {
+        failures.retain(|_, entry| match entry.locked_until {
+            Some(until) => until > now,
+            None => now.duration_since(entry.first) < window
+        });
+    }
+    let entry = failures.entry(ip).or_insert(Failures {
+        count: 0,
+        first: now,
+        locked_until: None
+    });
+    if entry.locked_until.is_none() && now.duration_since(entry.first) >= window {
+        entry.count = 0;
+        entry.first = now;
+    }
+    entry.count += 1;
+    if entry.count >= max_failures {
+        entry.locked_until = Some(now + window);
+    }
+}
+
+pub fn record_success(ip: IpAddr) {
+    let Some(failures) = FAILURES.get() else { return; };
+    if let Ok(mut failures) = failures.lock() {
+        failures.remove(&ip);
+    }
+}
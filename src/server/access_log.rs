@@ -0,0 +1,170 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, SystemTime}
+};
+use crate::{
+    json_escape,
+    date::{clf_date, iso8601},
+    error::Error,
+    logging
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // host ident authuser [date] "request" status bytes
+    Common,
+    // Common plus "referer" "user-agent" and the response time in milliseconds
+    Combined,
+    // One JSON object per line
+    Json
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<AccessLogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" | "clf" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!("unknown access log format \"{}\" (expected common, combined or json)", s))
+        }
+    }
+}
+
+pub struct AccessLogEntry {
+    pub client: Option<IpAddr>,
+    pub time: SystemTime,
+    // The request line as received, e.g. "GET /index.html?x=1 HTTP/1.1"
+    pub request_line: String,
+    pub method: String,
+    pub path: String,
+    pub status: i32,
+    // Response body bytes, without headers or chunked framing
+    pub bytes: u64,
+    pub referer: String,
+    pub user_agent: String,
+    pub duration: Duration
+}
+
+// Quoted CLF fields escape quotes, backslashes and control characters the way Apache does
+fn clf_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() { "-" } else { value }
+}
+
+impl AccessLogEntry {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let client = self.client.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("-"));
+        let bytes = if self.bytes == 0 { String::from("-") } else { self.bytes.to_string() };
+        let millis = self.duration.as_secs_f64() * 1000.0;
+        match format {
+            AccessLogFormat::Common => format!("{} - - [{}] \"{}\" {} {}",
+                client, clf_date(self.time), clf_escape(&self.request_line), self.status, bytes),
+            AccessLogFormat::Combined => format!("{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {:.3}",
+                client, clf_date(self.time), clf_escape(&self.request_line), self.status, bytes,
+                clf_escape(or_dash(&self.referer)), clf_escape(or_dash(&self.user_agent)), millis),
+            AccessLogFormat::Json => format!("{{\"time\":{},\"client\":{},\"method\":{},\"path\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"duration_ms\":{:.3}}}",
+                json_escape(&iso8601(self.time)), json_escape(&client), json_escape(&self.method), json_escape(&self.path),
+                self.status, self.bytes, json_escape(&self.referer), json_escape(&self.user_agent), millis)
+        }
+    }
+}
+
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: u32) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_size,
+            max_files
+        })
+    }
+    // access.log becomes access.log.1, access.log.1 becomes access.log.2, ... up to max_files
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+enum Target {
+    Stdout,
+    File(RotatingFile)
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    target: Target
+}
+
+impl AccessLog {
+    // `path` "-" writes to stdout. `max_size` 0 never rotates the file.
+    pub fn open(path: &str, format: AccessLogFormat, max_size: u64, max_files: u32) -> Result<AccessLog, Error> {
+        let target = if path == "-" {
+            Target::Stdout
+        } else {
+            Target::File(RotatingFile::open(path, max_size, max_files).map_err(|err| Error::from_fs(err, path))?)
+        };
+        Ok(AccessLog {
+            format,
+            target
+        })
+    }
+    pub fn write(&mut self, entry: &AccessLogEntry) {
+        let line = entry.format(self.format);
+        match &mut self.target {
+            Target::Stdout => println!("{}", line),
+            Target::File(file) => {
+                if let Err(err) = file.write_line(&line) {
+                    logging::error(format!("Failed to write access log {}: {}", file.path, err));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::SystemTime;
+use crate::{
+    date::http_date,
+    headers::is_valid_header_name
+};
+
+// Read-only view over the cookies sent in one or more Cookie request headers
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>
+}
+
+impl CookieJar {
+    pub fn parse(headers: &[&str]) -> CookieJar {
+        let mut cookies = Vec::new();
+        for header in headers.iter() {
+            for pair in header.split(';') {
+                let Some((name, value)) = pair.split_once('=') else { continue; };
+                let name = name.trim();
+                if name.is_empty() { continue; };
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                cookies.push((name.to_string(), value.to_string()));
+            }
+        }
+        CookieJar {
+            cookies
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.cookies.iter().any(|(key, _)| key == name)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.cookies.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None"
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<i64>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>
+}
+
+fn is_valid_cookie_value(value: &str) -> bool {
+    value.bytes().all(|b| b == 0x21 || (0x23..=0x2B).contains(&b) || (0x2D..=0x3A).contains(&b) || (0x3C..=0x5B).contains(&b) || (0x5D..=0x7E).contains(&b))
+}
+
+fn is_valid_attribute_value(value: &str) -> bool {
+    value.bytes().all(|b| (0x20..0x7F).contains(&b) && b != b';')
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> SetCookie {
+        SetCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None
+        }
+    }
+    // A cookie that tells the browser to delete `name` (the path/domain must match the original)
+    pub fn removal(name: &str) -> SetCookie {
+        SetCookie::new(name, "").max_age(0).expires(SystemTime::UNIX_EPOCH)
+    }
+    pub fn path(mut self, path: &str) -> SetCookie {
+        self.path = Some(path.to_string());
+        self
+    }
+    pub fn domain(mut self, domain: &str) -> SetCookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+    pub fn max_age(mut self, seconds: i64) -> SetCookie {
+        self.max_age = Some(seconds);
+        self
+    }
+    pub fn expires(mut self, time: SystemTime) -> SetCookie {
+        self.expires = Some(time);
+        self
+    }
+    pub fn secure(mut self, secure: bool) -> SetCookie {
+        self.secure = secure;
+        self
+    }
+    pub fn http_only(mut self, http_only: bool) -> SetCookie {
+        self.http_only = http_only;
+        self
+    }
+    pub fn same_site(mut self, same_site: SameSite) -> SetCookie {
+        self.same_site = Some(same_site);
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+    pub fn is_valid(&self) -> bool {
+        is_valid_header_name(&self.name) &&
+            is_valid_cookie_value(&self.value) &&
+            self.path.as_ref().map(|path| is_valid_attribute_value(path)).unwrap_or(true) &&
+            self.domain.as_ref().map(|domain| is_valid_attribute_value(domain)).unwrap_or(true)
+    }
+    pub fn to_header_value(&self) -> String {
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header += &format!("; Path={}", path);
+        }
+        if let Some(domain) = &self.domain {
+            header += &format!("; Domain={}", domain);
+        }
+        if let Some(max_age) = self.max_age {
+            header += &format!("; Max-Age={}", max_age);
+        }
+        if let Some(expires) = self.expires {
+            header += &format!("; Expires={}", http_date(expires));
+        }
+        if self.secure {
+            header += "; Secure";
+        }
+        if self.http_only {
+            header += "; HttpOnly";
+        }
+        if let Some(same_site) = self.same_site {
+            header += &format!("; SameSite={}", same_site.as_str());
+        }
+        header
+    }
+}
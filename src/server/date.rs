@@ -0,0 +1,82 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+static MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub weekday: usize
+}
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(dur) => dur.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64)
+        };
+        DateTime::from_unix(secs)
+    }
+    // Converts seconds since the unix epoch to a UTC civil date (Howard Hinnant's days_from_civil inverse)
+    pub fn from_unix(secs: i64) -> DateTime {
+        let days = secs.div_euclid(86400);
+        let rem = secs.rem_euclid(86400);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: ((rem % 3600) / 60) as u32,
+            second: (rem % 60) as u32,
+            weekday: days.rem_euclid(7) as usize
+        }
+    }
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[(self.month - 1) as usize]
+    }
+    pub fn day_name(&self) -> &'static str {
+        DAYS[self.weekday]
+    }
+}
+
+// Days since the unix epoch for a UTC civil date
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// IMF-fixdate, as used by the Date, Expires and Last-Modified headers
+pub fn http_date(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", date.day_name(), date.day, date.month_name(), date.year, date.hour, date.minute, date.second)
+}
+
+// Common Log Format timestamp, e.g. "10/Oct/2000:13:55:36 +0000"
+pub fn clf_date(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", date.day, date.month_name(), date.year, date.hour, date.minute, date.second)
+}
+
+// RFC 3339 / ISO 8601 UTC timestamp, e.g. "2000-10-10T13:55:36Z"
+pub fn iso8601(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", date.year, date.month, date.day, date.hour, date.minute, date.second)
+}
@@ -0,0 +1,75 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    // A listener could not be bound; `addr` is printable, e.g. "http://0.0.0.0:8080/" or "unix:/run/sws.sock"
+    Bind { addr: String, source: io::Error },
+    // Invalid settings, e.g. an unparsable listen address or IP filter entry
+    Config(String),
+    // The certificate or key could not be loaded into a TLS acceptor
+    Tls(openssl::error::ErrorStack),
+    Io(io::Error),
+    // The peer sent something that isn't valid HTTP/WebSocket, or the response was used out of order
+    Protocol(String),
+    ConnectionClosed,
+    NotFound(String),
+    PermissionDenied(String)
+}
+
+impl Error {
+    // Attaches the path to the file system errors callers most often want to tell apart
+    pub fn from_fs(err: io::Error, path: &str) -> Error {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(path.to_string()),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_string()),
+            _ => Error::Io(err)
+        }
+    }
+    // The HTTP status that best describes the error when it ends a request
+    pub fn status_code(&self) -> i32 {
+        match self {
+            Error::NotFound(_) => 404,
+            Error::PermissionDenied(_) => 403,
+            Error::Protocol(_) => 400,
+            _ => 500
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind { addr, source } => write!(f, "failed to listen on {}: {}", addr, source),
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::Tls(err) => write!(f, "TLS setup failed: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::ConnectionClosed => write!(f, "connection closed"),
+            Error::NotFound(path) => write!(f, "not found: {}", path),
+            Error::PermissionDenied(path) => write!(f, "permission denied: {}", path)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } => Some(source),
+            Error::Tls(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Error {
+        Error::Tls(err)
+    }
+}
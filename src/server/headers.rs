@@ -0,0 +1,88 @@
+// Ordered, case-insensitive, multi-valued header storage shared by requests and responses.
+#[derive(Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>
+}
+
+pub fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    })
+}
+
+pub fn is_valid_header_value(value: &str) -> bool {
+    !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap {
+            entries: Vec::new()
+        }
+    }
+    // Parses the header lines of a request head (the request line must already be stripped)
+    pub fn parse(lines: &[&str]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for line in lines.iter() {
+            let Some((name, value)) = line.split_once(':') else { continue; };
+            let name = name.trim();
+            if !is_valid_header_name(name) { continue; };
+            map.entries.push((name.to_string(), value.trim().to_string()));
+        }
+        map
+    }
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+    // Replaces every existing value of the header, keeping the position of the first one
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        if !is_valid_header_name(name) || !is_valid_header_value(value) {
+            return false;
+        }
+        let mut replaced = false;
+        self.entries.retain_mut(|(key, val)| {
+            if !key.eq_ignore_ascii_case(name) { return true; };
+            if replaced { return false; };
+            *key = name.to_string();
+            *val = value.to_string();
+            replaced = true;
+            true
+        });
+        if !replaced {
+            self.entries.push((name.to_string(), value.to_string()));
+        }
+        true
+    }
+    pub fn append(&mut self, name: &str, value: &str) -> bool {
+        if !is_valid_header_name(name) || !is_valid_header_value(value) {
+            return false;
+        }
+        self.entries.push((name.to_string(), value.to_string()));
+        true
+    }
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        len != self.entries.len()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
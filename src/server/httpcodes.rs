@@ -1,4 +1,4 @@
-static HTTP_MESSAGES: [(i32, &str); 42] = [
+static HTTP_MESSAGES: [(i32, &str); 43] = [
     (100, "Continue"),
     (101, "Switching Protocols"),
     (200, "OK"),
@@ -34,6 +34,7 @@ static HTTP_MESSAGES: [(i32, &str); 42] = [
     (415, "Unsupported Media Type"),
     (416, "Requested Range Not Satisfiable"),
     (417, "Expectation Failed"),
+    (426, "Upgrade Required"),
     (429, "Too Many Requests"),
     (500, "Internal Server Error"),
     (501, "Not Implemented"),
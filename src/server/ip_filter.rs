@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+
+#[derive(Copy, Clone)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+// IPv4-mapped IPv6 addresses (from dual-stack listeners) are treated as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip
+    }
+}
+
+impl Cidr {
+    // Accepts a single address ("10.0.0.5", "::1") or a range ("192.168.1.0/24", "fd00::/8")
+    pub fn parse(input: &str) -> Option<Cidr> {
+        let (addr, prefix) = match input.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().ok()?)),
+            None => (input.trim(), None)
+        };
+        let addr = canonical(addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max { return None; };
+        Some(Cidr {
+            addr,
+            prefix
+        })
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>
+}
+
+impl IpFilter {
+    pub fn new(allow: &[&str], deny: &[&str]) -> Result<IpFilter, String> {
+        let parse = |list: &[&str]| list.iter()
+            .map(|entry| Cidr::parse(entry).ok_or(format!("Invalid IP address or CIDR range \"{}\"", entry)))
+            .collect::<Result<Vec<_>, String>>();
+        Ok(IpFilter {
+            allow: parse(allow)?,
+            deny: parse(deny)?
+        })
+    }
+    // The deny list wins; a non-empty allow list must also match
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
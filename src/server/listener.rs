@@ -0,0 +1,85 @@
+use std::{
+    io,
+    net::{TcpListener, TcpStream, SocketAddr, IpAddr, ToSocketAddrs}
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream}
+    }
+};
+use socket2::{Socket as RawSocket, Domain, Type, Protocol};
+
+pub enum BoundListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String)
+}
+
+pub enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl BoundListener {
+    pub fn accept(&self) -> io::Result<Incoming> {
+        match self {
+            BoundListener::Tcp(listener) => listener.accept().map(|(stream, _)| Incoming::Tcp(stream)),
+            #[cfg(unix)]
+            BoundListener::Unix(listener, _) => listener.accept().map(|(stream, _)| Incoming::Unix(stream))
+        }
+    }
+    pub fn close(self) {
+        #[cfg(unix)]
+        if let BoundListener::Unix(listener, path) = self {
+            drop(listener);
+            if !path.is_empty() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+pub fn resolve_listen_addr(host: &str, port: i32) -> Option<SocketAddr> {
+    let port = u16::try_from(port).ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port));
+    }
+    (host, port).to_socket_addrs().ok()?.next()
+}
+
+// "::" always accepts IPv4 too (as v4-mapped addresses), whatever the OS default for IPV6_V6ONLY is
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = RawSocket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// `mode` sets the socket file permissions (e.g. 0o660), 0 leaves them to the umask
+#[cfg(unix)]
+pub fn bind_unix(path: &str, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        // Clean up after a previous run, but never steal the socket of a server that is still up
+        if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "socket path is in use"));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if mode != 0 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
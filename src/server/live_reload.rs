@@ -0,0 +1,108 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant}
+};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use crate::{
+    Settings,
+    json_escape,
+    error::Error,
+    logging,
+    wshub,
+    wsparser::{Message, WebSocketParser}
+};
+
+static SCRIPT: &str = include_str!("live-reload-script.html");
+// Browsers connect here; the path is also baked into the injected script
+pub const LIVE_RELOAD_PATH: &str = "/__livereload";
+const CHANNEL: &str = "__livereload";
+// Editors save in several steps (temp file, rename, chmod); wait for them to settle, but no longer
+// than DEBOUNCE * MAX_DEBOUNCE_STEPS when files keep changing (e.g. during a build)
+const DEBOUNCE: Duration = Duration::from_millis(100);
+const MAX_DEBOUNCE_STEPS: u32 = 5;
+// Larger HTML files are served as they are
+pub const MAX_INJECT_SIZE: u64 = 8 * 1024 * 1024;
+
+// Watches `root` recursively and tells connected browsers about changes until the process exits
+pub fn watch(root: &str) -> Result<(), Error> {
+    let root = PathBuf::from(root).canonicalize().map_err(|err| Error::from_fs(err, root))?;
+    let (sender, receiver) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(sender)
+        .map_err(|err| Error::Io(std::io::Error::other(err.to_string())))?;
+    watcher.watch(&root, RecursiveMode::Recursive)
+        .map_err(|err| Error::Io(std::io::Error::other(format!("cannot watch {}: {}", root.display(), err))))?;
+    logging::info(format!("Live reload: watching {} for changes", root.display()));
+    thread::spawn(move || {
+        // Moved in so the watch lasts as long as this thread
+        let _watcher = watcher;
+        while let Ok(event) = receiver.recv() {
+            let mut changed = Vec::new();
+            collect_changes(&root, event, &mut changed);
+            let deadline = Instant::now() + DEBOUNCE * MAX_DEBOUNCE_STEPS;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() { break; };
+                let Ok(event) = receiver.recv_timeout(DEBOUNCE.min(remaining)) else { break; };
+                collect_changes(&root, event, &mut changed);
+            }
+            if !changed.is_empty() {
+                notify_browsers(&changed);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Adds the URL paths of changed files, skipping hidden files (which aren't served) and editor backups
+fn collect_changes(root: &Path, event: notify::Result<Event>, changed: &mut Vec<String>) {
+    let Ok(event) = event else { return; };
+    if matches!(event.kind, EventKind::Access(_)) { return; };
+    'paths: for path in event.paths {
+        let Ok(relative) = path.strip_prefix(root) else { continue; };
+        let mut url = String::new();
+        for component in relative.components() {
+            let Component::Normal(name) = component else { continue; };
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || name.ends_with('~') { continue 'paths; };
+            url.push('/');
+            url.push_str(&name);
+        }
+        if !url.is_empty() && !changed.contains(&url) {
+            changed.push(url);
+        }
+    }
+}
+
+// Stylesheets are swapped in place when nothing else changed; anything else reloads the page
+fn notify_browsers(changed: &[String]) {
+    let hub = wshub::global();
+    if changed.iter().all(|path| path.to_ascii_lowercase().ends_with(".css")) {
+        for path in changed {
+            logging::debug(format!("Live reload: {} changed", path));
+            hub.broadcast(CHANNEL, &Message::Text(format!("{{\"type\":\"css\",\"path\":{}}}", json_escape(path))));
+        }
+    } else {
+        logging::debug(format!("Live reload: {} changed", changed.join(", ")));
+        hub.broadcast(CHANNEL, &Message::Text(String::from("{\"type\":\"reload\"}")));
+    }
+}
+
+pub fn on_websocket(mut ws: WebSocketParser, _opts: Settings) {
+    let hub = wshub::global();
+    let sender = ws.sender();
+    hub.join(CHANNEL, &sender);
+    // Only here to notice the browser going away; it never sends anything we need
+    while ws.connected() {
+        if ws.recv_message().is_err() { break; };
+    }
+    hub.leave_all(&sender);
+}
+
+// Puts the client script right before </body>, or at the end if there is none
+pub fn inject_script(html: &mut Vec<u8>) {
+    let lower = html.to_ascii_lowercase();
+    let position = lower.windows(7).rposition(|window| window == b"</body>").unwrap_or(html.len());
+    html.splice(position..position, SCRIPT.bytes());
+}
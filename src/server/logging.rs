@@ -0,0 +1,113 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        RwLock,
+        atomic::{AtomicU8, Ordering}
+    },
+    time::SystemTime
+};
+use crate::date::iso8601;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG"
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!("unknown log level \"{}\" (expected error, warn, info or debug)", s))
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+// Receives every message at or above the configured level. Install one with set_logger to route
+// the server's diagnostics into an embedding application's own logging.
+pub trait Logger: Send + Sync {
+    fn log(&self, level: Level, message: &str);
+}
+
+// The default: errors and warnings go to stderr, everything else to stdout
+pub struct ConsoleLogger;
+
+impl Logger for ConsoleLogger {
+    fn log(&self, level: Level, message: &str) {
+        let line = format!("{} {:<5} {}", iso8601(SystemTime::now()), level, message);
+        if level <= Level::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static LOGGER: RwLock<Option<Box<dyn Logger>>> = RwLock::new(None);
+
+pub fn set_logger(logger: Box<dyn Logger>) {
+    if let Ok(mut current) = LOGGER.write() {
+        *current = Some(logger);
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, message: impl fmt::Display) {
+    if !enabled(level) { return; };
+    let message = message.to_string();
+    match LOGGER.read() {
+        Ok(logger) => match logger.as_ref() {
+            Some(logger) => logger.log(level, &message),
+            None => ConsoleLogger.log(level, &message)
+        },
+        Err(_) => ConsoleLogger.log(level, &message)
+    }
+}
+
+pub fn error(message: impl fmt::Display) {
+    log(Level::Error, message);
+}
+
+pub fn warn(message: impl fmt::Display) {
+    log(Level::Warn, message);
+}
+
+pub fn info(message: impl fmt::Display) {
+    log(Level::Info, message);
+}
+
+pub fn debug(message: impl fmt::Display) {
+    log(Level::Debug, message);
+}
@@ -1,11 +1,12 @@
 use std::{
-    net::TcpListener,
+    fmt,
+    net::SocketAddr,
     thread,
     io::{Read, Write, SeekFrom, Seek},
     fs,
     fs::File,
     str,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
     sync::{
         Arc,
         mpsc,
@@ -21,6 +22,21 @@ pub mod file_system;
 pub mod mime;
 pub mod httpcodes;
 pub mod wsparser;
+mod wsdeflate;
+pub mod wsroutes;
+pub mod wshub;
+pub mod live_reload;
+pub mod sse;
+pub mod headers;
+pub mod cookies;
+pub mod date;
+pub mod ip_filter;
+pub mod error;
+pub mod logging;
+pub mod access_log;
+pub mod metrics;
+mod listener;
+pub mod systemd;
 mod socket_handler;
 mod socket;
 
@@ -30,7 +46,14 @@ use crate::{
     httpcodes::get_http_message,
     socket_handler::SocketHandler,
     socket::Socket,
-    wsparser::WebSocketParser
+    wsparser::WebSocketParser,
+    wsroutes::WebSocketRoutes,
+    headers::HeaderMap,
+    cookies::{CookieJar, SetCookie},
+    ip_filter::IpFilter,
+    error::Error,
+    access_log::{AccessLog, AccessLogEntry, AccessLogFormat},
+    listener::{BoundListener, Incoming, resolve_listen_addr, bind_tcp}
 };
 
 use openssl::{
@@ -120,7 +143,19 @@ pub fn generate_dummy_cert_and_key() -> Result<(String, String), openssl::error:
 
 #[derive(Copy, Clone)]
 pub struct Settings<'a> {
+    // 0 binds a free ephemeral port, see Server::local_addrs for the one that was picked
     pub port: i32,
+    // Try up to this many following ports when the requested one is already in use
+    pub port_auto_increment: u16,
+    // Exact address to bind instead of choosing one from local_network/ipv6
+    pub bind: &'a str,
+    // Replaces the single listener from bind/port/https when not empty
+    pub listeners: &'a [Listener<'a>],
+    // Listen on this Unix domain socket instead of the default TCP listener
+    pub unix_socket: &'a str,
+    pub unix_socket_mode: u32,
+    // (fd, https) for the listening sockets passed by systemd, from systemd::take_listen_fds; used instead of binding when not empty
+    pub systemd_listen_fds: &'a [(i32, bool)],
     pub path: &'a str,
     pub local_network: bool,
     pub spa: bool,
@@ -141,12 +176,93 @@ pub struct Settings<'a> {
     pub http_auth: bool,
     pub http_auth_username: &'a str,
     pub http_auth_password: &'a str,
+    pub http_auth_users_file: &'a str,
+    pub http_auth_tokens_file: &'a str,
+    pub http_auth_login_page: bool,
+    pub http_auth_digest: bool,
+    pub acl_file: &'a str,
+    pub ip_allowlist: &'a [&'a str],
+    pub ip_denylist: &'a [&'a str],
+    pub rate_limit: u32,
+    pub rate_limit_burst: u32,
+    pub auth_max_failures: u32,
+    pub auth_lockout: u64,
+    pub session_secret: &'a str,
+    pub session_timeout: u64,
+    // "" disables the access log, "-" writes it to stdout, anything else is a file path
+    pub access_log: &'a str,
+    pub access_log_format: AccessLogFormat,
+    // Rotate the access log file once it would grow past this many bytes (0 = never)
+    pub access_log_max_size: u64,
+    // Rotated access log files to keep (access.log.1 .. access.log.N)
+    pub access_log_max_files: u32,
+    // Serve Prometheus metrics at this path ("" disables them). On the regular listeners on_request
+    // has to answer it with serve_metrics, so the path gets the same access checks as everything else
+    pub metrics_path: &'a str,
+    // Serve metrics only on this separate port instead of on the regular listeners (0 = regular listeners)
+    pub metrics_port: i32,
+    // Answer GET /healthz with 200 "ok" before rate limiting and authentication
+    pub health_check: bool,
+    // Report uptime, enabled features and traffic at /status (HTML, or JSON with ?format=json)
+    pub status_page: bool,
+    // Ping WebSocket clients after this many seconds without traffic (0 = never)
+    pub websocket_ping_interval: u64,
+    // Close WebSocket connections after this many seconds without traffic, pongs included (0 = never)
+    pub websocket_idle_timeout: u64,
+    // Negotiate permessage-deflate with WebSocket clients that offer it
+    pub websocket_compression: bool,
+    // Reset the compressor after every message instead of keeping a 32KiB window per connection
+    pub websocket_no_context_takeover: bool,
+    // Subprotocols the WebSocket handler speaks; a client offering only others is refused (empty = don't negotiate)
+    pub websocket_protocols: &'a [&'a str],
+    // Origins allowed to open WebSockets, e.g. "https://example.com" (empty = any)
+    pub websocket_origins: &'a [&'a str],
+    // Serve a WebSocket echo endpoint at this path ("" = off)
+    pub websocket_echo: &'a str,
+    // Serve a pub-sub endpoint at this path: clients join the channels named in ?channel=... and
+    // everything they send is relayed to the other members ("" = off)
+    pub websocket_broadcast: &'a str,
+    // Watch `path` and reload browsers showing its HTML pages when files change
+    pub live_reload: bool,
     pub index: bool,
     pub https: bool,
     pub https_cert: &'a str,
     pub https_key: &'a str
 }
 
+#[derive(Copy, Clone)]
+pub struct Listener<'a> {
+    // An IP address ("0.0.0.0", "::", "[::]", "192.168.1.5") or host name
+    pub host: &'a str,
+    pub port: i32,
+    pub https: bool
+}
+
+// The address a listener actually ended up on, e.g. with the real port when 0 was requested
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp { addr: SocketAddr, https: bool },
+    Unix(String)
+}
+
+impl ListenAddr {
+    pub fn tcp(&self) -> Option<SocketAddr> {
+        match self {
+            ListenAddr::Tcp { addr, .. } => Some(*addr),
+            ListenAddr::Unix(_) => None
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp { addr, https } => write!(f, "{}://{}/", if *https { "https" } else { "http" }, addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path)
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn url_decode(input: &str) -> String {
     let mut decoded = String::new();
@@ -194,6 +310,48 @@ pub fn url_decode(input: &str) -> String {
     decoded
 }
 
+// Parses an application/x-www-form-urlencoded string (a query string or form body)
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    for pair in query.split('&') {
+        if pair.is_empty() { continue; };
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.push((url_decode(&name.replace('+', " ")), url_decode(&value.replace('+', " "))));
+    }
+    params
+}
+
+pub fn url_encode(input: &str) -> String {
+    let mut encoded = String::new();
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded += &format!("%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+// Quotes and escapes a string for embedding in JSON
+pub fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
 fn is_hidden(path: &String) -> bool {
     let components: Vec<&str> = path.split('/').collect();
     for component in components.iter() {
@@ -255,84 +413,89 @@ fn strip_off_file(orig_path: &str) -> String {
     orig_path[0..last_slash_idx].to_string()
 }
 
-#[allow(dead_code)]
-#[allow(unused_assignments)]
-struct Header {
-    name: String,
-    value: String
-}
-#[allow(dead_code)]
-#[allow(unused_assignments)]
-impl Header {
-    pub fn new(header:&str, value:&str) -> Header {
-        Header {
-            name: header.to_string(),
-            value: value.to_string()
+// The inclusive byte span a single "bytes=start-end", "bytes=start-" or "bytes=-suffix" range selects,
+// or None if the header is malformed or cannot be satisfied by a file of `size` bytes
+fn parse_byte_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let (unit, spec) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || size == 0 {
+            return None;
         }
+        return Some((size.saturating_sub(suffix), size - 1));
+    }
+    let start = start.parse::<u64>().ok()?;
+    let end = if end.is_empty() { u64::MAX } else { end.parse::<u64>().ok()? };
+    if start >= size || start > end {
+        return None;
     }
+    Some((start, end.min(size - 1)))
 }
 
-
 #[allow(dead_code)]
 #[allow(unused_assignments)]
 pub struct Request<'a> {
     pub path: String,
     pub origpath: String,
+    pub query: String,
     pub method: String,
+    request_line: String,
     stream: &'a mut Socket,
-    headers: Vec<Header>,
-    out_headers: Vec<Header>,
+    headers: HeaderMap,
+    out_headers: HeaderMap,
     status_code: i32,
     status_message: String,
     headers_written: bool,
     length: usize,
     consumed: usize,
     finished: bool,
-    connection_closed: bool
+    connection_closed: bool,
+    started: Instant,
+    bytes_sent: u64,
+    access_log: Option<Arc<Mutex<AccessLog>>>,
+    // Inject the live reload script into HTML served by send_file
+    live_reload: bool
 }
 
 #[allow(dead_code)]
 #[allow(unused_assignments)]
 impl Request<'_> {
-    pub fn new(stream:&mut Socket, head:String) -> Request {
+    pub fn new(stream:&mut Socket, head:String) -> Request<'_> {
         let lines = head.split("\r\n").collect::<Vec<_>>();
         let parts = lines[0].split(' ').collect::<Vec<_>>();
-        let mut headers = Vec::new();
-        let mut length = 0;
-        for (i, line) in lines.iter().enumerate() {
-            if i == 0 { continue; };
-            let header_split = line.split(": ").collect::<Vec<_>>();
-            if header_split.len() < 2 { continue; };
-            headers.push(Header::new(header_split[0], header_split[1]));
-            if header_split[0].to_lowercase() == "content-length" {
-                match header_split[1].parse::<usize>() {
-                    Ok(num) => {
-                        length = num;
-                    },
-                    Err(_e) => {/*nada*/},
-                }
-            }
-        }
+        let headers = HeaderMap::parse(&lines[1..]);
+        let length = headers.get("content-length").and_then(|len| len.parse::<usize>().ok()).unwrap_or(0);
         let path = relative_path("", &url_decode(parts[1].splitn(2, '?').collect::<Vec<_>>()[0]));
         let origpath = relative_path("", parts[1].splitn(2, '?').collect::<Vec<_>>()[0]);
-        //todo, parse url arguments
+        let query = parts[1].split_once('?').map(|(_, query)| query.to_string()).unwrap_or_default();
         Request {
             method: parts[0].to_string(),
+            request_line: lines[0].to_string(),
             path,
             origpath,
+            query,
             stream,
             headers,
-            out_headers: Vec::new(),
+            out_headers: HeaderMap::new(),
             status_code: 200,
             status_message: String::from("OK"),
             headers_written: false,
             length,
             consumed: 0,
             finished: false,
-            connection_closed: false
+            connection_closed: false,
+            started: Instant::now(),
+            bytes_sent: 0,
+            access_log: None,
+            live_reload: false
         }
     }
-    pub fn read(&mut self, bytes:usize) -> Result<Vec<u8>, bool> {
+    pub fn read(&mut self, bytes:usize) -> Result<Vec<u8>, Error> {
         let mut bytes = bytes;
         if self.consumed + bytes > self.length || bytes == 0 {
             //Consume the whole/rest of the body
@@ -349,7 +512,7 @@ impl Request<'_> {
                 Ok(bytes_read) => {
                     if bytes_read == 0 {
                         self.connection_closed = true;
-                        return Err(true);
+                        return Err(Error::ConnectionClosed);
                     }
                     read += bytes_read;
                     //println!("{} bytes read", bytes_read);
@@ -359,52 +522,42 @@ impl Request<'_> {
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     thread::sleep(Duration::from_millis(10));
                 }
-                Err(_) => {
+                Err(e) => {
                     self.connection_closed = true;
-                    return Err(true);
+                    return Err(Error::Io(e));
                 }
             }
         }
+        self.consumed += read;
         Ok(buffer)
     }
     //Will truncate the file
-    pub fn write_to_file(&mut self, path: &str) -> bool {
+    pub fn write_to_file(&mut self, path: &str) -> Result<(), Error> {
         let read_chunk_size = 1024 * 1024 * 4;
-        let Ok(mut file) = File::create(path) else {
-            return false;
-        };
+        let mut file = File::create(path).map_err(|err| Error::from_fs(err, path))?;
         let mut done = false;
         while !done {
             match self.read(read_chunk_size) {
                 Ok(read) => {
                     done = read == b"";
-                    match file.write(&read) {
-                        Ok(_) => {},
-                        Err(_e) => {
-                            //uhh. what do we do here...
-                            return false;
-                        }
-                    }
+                    file.write_all(&read)?;
                 }
-                Err(fatal) => {
+                Err(err) => {
                     self.connection_closed = true;
                     self.stream.shutdown();
-                    if fatal { return false; };
+                    return Err(err);
                 }
             }
         }
-        true
+        Ok(())
     }
     fn consume_body(&mut self) {
         let read_chunk_size = 1024 * 1024 * 4;
         while self.consumed != self.length {
-            match self.read(read_chunk_size) {
-                Ok(_) => {},
-                Err(fatal) => {
-                    self.connection_closed = true;
-                    self.stream.shutdown();
-                    if fatal { return; };
-                }
+            if self.read(read_chunk_size).is_err() {
+                self.connection_closed = true;
+                self.stream.shutdown();
+                return;
             }
         }
     }
@@ -418,7 +571,7 @@ impl Request<'_> {
     }
     fn send_headers(&mut self) {
         if self.headers_written {
-            println!("Headers already sent!");
+            logging::warn("Headers already sent!");
             return;
         }
         if !self.header_exists("Content-Length") {
@@ -426,8 +579,8 @@ impl Request<'_> {
         }
         //TODO - send date header
         let mut header = format!("HTTP/1.1 {} {}", self.status_code, self.status_message);
-        for value in self.out_headers.iter() {
-            header += &format!("\r\n{}:{}", value.name, value.value);
+        for (name, value) in self.out_headers.iter() {
+            header += &format!("\r\n{}:{}", name, value);
         }
         header += "\r\n\r\n";
         self.write_to_stream(header.as_bytes());
@@ -462,6 +615,7 @@ impl Request<'_> {
             self.write_to_stream((format!("{:x}", data.len())+"\r\n").as_bytes());
         }
         self.write_to_stream(data);
+        self.bytes_sent += data.len() as u64;
         if chunked {
             self.write_to_stream("\r\n".as_bytes());
         }
@@ -483,45 +637,104 @@ impl Request<'_> {
     pub fn write_string(&mut self, data:&str) {
         self.write(data.to_string().as_bytes());
     }
-    pub fn get_header(&mut self, header:&str) -> String {
-        let head = self.format_header(header).to_string();
-        for key in self.headers.iter() {
-            if key.name == head {
-                return key.value.as_str().to_string();
-            }
+    // Turns the response into a Server-Sent Events stream. Send with send_event and finish with
+    // end(); each event goes out as one chunk of the chunked response.
+    pub fn start_event_stream(&mut self) -> Result<(), Error> {
+        if self.headers_written {
+            return Err(Error::Protocol(String::from("headers must not yet be sent when starting an event stream")));
         }
-        String::new()
+        self.remove_header("Content-Length");
+        self.set_status(200);
+        self.set_header("Content-Type", "text/event-stream");
+        self.set_header("Cache-Control", "no-cache");
+        // Keeps reverse proxies such as nginx from buffering the stream
+        self.set_header("X-Accel-Buffering", "no");
+        self.send_headers();
+        Ok(())
     }
-    pub fn header_value_equals(&mut self, header:&str, value:&str) -> bool {
-        let head = header.to_string();
-        let val = value.to_string();
-        for key in self.out_headers.iter() {
-            if key.name == head {
-                return key.value.to_lowercase() == val.to_lowercase();
-            }
-        }
-        false
+    // The id of the last event a reconnecting EventSource saw (see sse::EventLog::since)
+    pub fn last_event_id(&self) -> Option<String> {
+        let id = self.get_header("Last-Event-ID");
+        if id.is_empty() { None } else { Some(id) }
     }
-    pub fn header_exists(&mut self, header:&str) -> bool {
-        let head = header.to_string();
-        for key in self.out_headers.iter() {
-            if key.name == head {
-                return true;
-            }
+    // Starts the stream if needed. Returns false once the client is gone.
+    pub fn send_event(&mut self, event: &sse::Event) -> bool {
+        if !self.headers_written && self.start_event_stream().is_err() { return false; };
+        self.write(event.encode().as_bytes());
+        !self.connection_closed
+    }
+    // A comment clients ignore; sending one now and then keeps idle streams open
+    pub fn send_event_comment(&mut self, text: &str) -> bool {
+        if !self.headers_written && self.start_event_stream().is_err() { return false; };
+        self.write(sse::comment(text).as_bytes());
+        !self.connection_closed
+    }
+    // Forwards events until every sender is dropped or the client goes away, sending a heartbeat
+    // comment whenever nothing happened for `heartbeat`, then ends the response
+    pub fn stream_events(&mut self, events: &mpsc::Receiver<sse::Event>, heartbeat: Duration) {
+        if !self.headers_written && self.start_event_stream().is_err() { return; };
+        loop {
+            let sent = match events.recv_timeout(heartbeat) {
+                Ok(event) => self.send_event(&event),
+                Err(mpsc::RecvTimeoutError::Timeout) => self.send_event_comment("heartbeat"),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            };
+            if !sent { break; };
         }
-        false
+        self.end();
     }
-    pub fn set_header(&mut self, header:&str, value:&str) {
-        // Are these header names and values valid?
-        let new_header = Header::new(self.format_header(header).as_str(), value);
-        for key in self.out_headers.iter_mut() {
-            if key.name == new_header.name {
-                *key = new_header;
-                return;
-            }
+    pub fn get_header(&self, header:&str) -> String {
+        self.headers.get(header).unwrap_or("").to_string()
+    }
+    pub fn get_headers(&self, header:&str) -> Vec<String> {
+        self.headers.get_all(header).iter().map(|value| value.to_string()).collect()
+    }
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    // None for connections that don't have an IP address
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr()
+    }
+    // Whether this connection came in over TLS, whichever listener accepted it
+    pub fn is_https(&self) -> bool {
+        self.stream.is_tls()
+    }
+    pub fn get_query(&self, name:&str) -> Option<String> {
+        parse_query(&self.query).into_iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+    pub fn cookies(&self) -> CookieJar {
+        CookieJar::parse(&self.headers.get_all("cookie"))
+    }
+    pub fn get_cookie(&self, name:&str) -> Option<String> {
+        self.cookies().get(name).map(|value| value.to_string())
+    }
+    // Returns false if the cookie name, value or attributes are not valid
+    pub fn set_cookie(&mut self, cookie:&SetCookie) -> bool {
+        if !cookie.is_valid() { return false; };
+        self.append_header("Set-Cookie", &cookie.to_header_value())
+    }
+    pub fn header_value_equals(&self, header:&str, value:&str) -> bool {
+        match self.out_headers.get(header) {
+            Some(val) => val.eq_ignore_ascii_case(value),
+            None => false
         }
-        
-        self.out_headers.push(new_header);
+    }
+    pub fn header_exists(&self, header:&str) -> bool {
+        self.out_headers.contains(header)
+    }
+    // Returns false (and sets nothing) if the name or value would allow header injection
+    pub fn set_header(&mut self, header:&str, value:&str) -> bool {
+        let name = self.format_header(header);
+        self.out_headers.set(&name, value)
+    }
+    // Adds another value for the header instead of replacing it (e.g. multiple Set-Cookie headers)
+    pub fn append_header(&mut self, header:&str, value:&str) -> bool {
+        let name = self.format_header(header);
+        self.out_headers.append(&name, value)
+    }
+    pub fn remove_header(&mut self, header:&str) -> bool {
+        self.out_headers.remove(header)
     }
     pub fn set_status(&mut self, code:i32) {
         self.status_code = code;
@@ -533,22 +746,41 @@ impl Request<'_> {
         if !self.headers_written { self.send_headers(); };
         self.finished = true;
         let chunked = self.header_value_equals("Transfer-Encoding", "Chunked");
+        // A Content-Length body is already complete; anything more would corrupt the next response on this connection
         if chunked {
             self.write_to_stream("0\r\n\r\n".as_bytes());
-        } else {
-            self.write_to_stream("\r\n\r\n".as_bytes());
         }
+        self.record_completion();
     }
-    //The directory_listing and send_file functions will return either 404, 500, or 200
-    pub fn directory_listing(&mut self, path:&str, no_body:bool, dot_files:bool) -> i32 {
+    // Feeds the finished response into the metrics and the access log
+    fn record_completion(&self) {
+        let duration = self.started.elapsed();
+        metrics::record_request(&self.method, self.status_code, self.bytes_sent, duration);
+        let Some(access_log) = self.access_log.as_ref() else { return; };
+        let entry = AccessLogEntry {
+            client: self.peer_addr().map(|addr| addr.ip()),
+            time: SystemTime::now(),
+            request_line: self.request_line.clone(),
+            method: self.method.clone(),
+            path: if self.query.is_empty() { self.origpath.clone() } else { format!("{}?{}", self.origpath, self.query) },
+            status: self.status_code,
+            bytes: self.bytes_sent,
+            referer: self.get_header("referer"),
+            user_agent: self.get_header("user-agent"),
+            duration
+        };
+        if let Ok(mut access_log) = access_log.lock() {
+            access_log.write(&entry);
+        }
+    }
+    // On error nothing has been sent yet (unless the connection broke), so the caller can still respond
+    // with Error::status_code
+    pub fn directory_listing(&mut self, path:&str, no_body:bool, dot_files:bool) -> Result<(), Error> {
         if self.headers_written {
-            println!("Headers must not yet be sent when using send_file");
-            return 500;
+            return Err(Error::Protocol(String::from("headers must not yet be sent when using directory_listing")));
         }
+        let paths = fs::read_dir(path).map_err(|err| Error::from_fs(err, path))?;
         self.set_header("content-type", "text/html; charset=utf-8");
-        let Ok(paths) = fs::read_dir(path) else {
-            return 404;
-        };
         let mut to_send = String::from("<!DOCTYPE html>\n<html dir=\"ltr\" lang=\"en\n<head><meta charset=\"utf-8\"><meta name=\"google\" value=\"notranslate\"><title id=\"title\"></title>\n</head>\n<body><div id=\"staticListing\"><style>li.directory {background:#aab}</style><a href=\"../\">parent</a><ul>");
         let mut js_listing = String::new();
         for path in paths {
@@ -589,61 +821,62 @@ impl Request<'_> {
         }
         self.end();
         
-        200
+        Ok(())
     }
-    pub fn send_file(&mut self, path:&str, no_body:bool) -> i32 {
+    pub fn send_file(&mut self, path:&str, no_body:bool) -> Result<(), Error> {
         if self.headers_written {
-            println!("Headers must not yet be sent when using send_file");
-            return 500;
+            return Err(Error::Protocol(String::from("headers must not yet be sent when using send_file")));
         }
         //println!("Rendering file at {}", path);
         let read_chunk_size : u64 = 1024 * 1024 * 8;
-        let Ok(mut file) = File::open(path) else {
-            return 404;
-        };
+        let mut file = File::open(path).map_err(|err| Error::from_fs(err, path))?;
         let ext = path.split('.').last().unwrap_or("");
         let ct = get_mime_type(ext);
         if !ct.is_empty() {
             self.set_header("content-type", &ct);
         }
-        let Ok(metadata) = file.metadata() else {
-            return 500;
-        };
+        let metadata = file.metadata()?;
         let size : u64 = metadata.len();
         let mut written : u64 = 0;
         
         let mut file_offset : u64 = 0;
-        let mut file_end_offset : u64 = size - 1;
         let mut content_length : u64 = size;
         let mut code = 200;
         let range_header = self.get_header("Range");
+        if self.live_reload && ct.starts_with("text/html") && range_header.is_empty() && size <= live_reload::MAX_INJECT_SIZE {
+            let mut html = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut html)?;
+            live_reload::inject_script(&mut html);
+            self.set_header("content-length", &html.len().to_string());
+            self.set_header("cache-control", "no-store");
+            self.set_status(200);
+            if !no_body {
+                self.write(&html);
+            }
+            self.end();
+            return Ok(());
+        }
+        // Nothing to take a range of; answer every request for an empty file with an empty 200
+        if size == 0 {
+            self.set_header("content-length", "0");
+            self.set_status(200);
+            self.end();
+            return Ok(());
+        }
         //println!("{}", self.get_header("Range"));
-        if range_header != String::new() {
+        if !range_header.is_empty() {
             //println!("Range Request");
-            let range = range_header.split('=').collect::<Vec<_>>()[1].trim();
-            let rparts = range.split('-').collect::<Vec<_>>();
-            file_offset = rparts[0].parse::<u64>().unwrap_or(0);
-            //println!("{} {}", range_header, rparts[1].len());
-            if rparts[1].is_empty() {
-                content_length = size - file_offset;
-                
-                if file_offset > file_end_offset {
-                    file_offset = file_end_offset;
-                }
-                self.set_header("content-range", &format!("bytes {}-{}/{}", file_offset, size-1, size));
-                code = if file_offset == 0 { 200 } else { 206 };
-            } else {
-                let new_end_offset = rparts[1].parse::<u64>().unwrap_or(0);
-                if new_end_offset < file_end_offset {
-                    file_end_offset = new_end_offset;
-                }
-                if file_offset > file_end_offset {
-                    file_offset = file_end_offset;
-                }
-                content_length = file_end_offset - file_offset + 1;
-                self.set_header("content-range", &format!("bytes {}-{}/{}", file_offset, file_end_offset, size));
-                code = 206;
-            }
+            let Some((start, end)) = parse_byte_range(&range_header, size) else {
+                self.set_header("content-range", &format!("bytes */{}", size));
+                self.set_header("content-length", "0");
+                self.set_status(416);
+                self.end();
+                return Ok(());
+            };
+            file_offset = start;
+            content_length = end - start + 1;
+            self.set_header("content-range", &format!("bytes {}-{}/{}", start, end, size));
+            code = if content_length == size { 200 } else { 206 };
         }
         
         self.set_header("content-length", &content_length.to_string());
@@ -651,27 +884,32 @@ impl Request<'_> {
         if no_body {
             drop(file);
             self.end();
-            return 200;
+            return Ok(());
         }
-        let Ok(_) = file.seek(SeekFrom::Start(file_offset)) else { return 500; };
+        file.seek(SeekFrom::Start(file_offset))?;
         while written < content_length {
             if self.connection_closed { break; };
             let chunk_size : u64 = if content_length-written > read_chunk_size { read_chunk_size } else { content_length-written };
             if chunk_size == 0 { break; };
             let mut buffer = vec![0; chunk_size as usize];
-            let Ok(_) = file.read(&mut buffer) else { todo!() };
+            if let Err(err) = file.read_exact(&mut buffer) {
+                // Part of the response is already out, so the connection can't be reused for an error page
+                self.connection_closed = true;
+                self.stream.shutdown();
+                return Err(Error::Io(err));
+            }
             self.write(&buffer);
             written += chunk_size;
         }
         drop(file);
         self.end();
-        200
+        Ok(())
     }
 }
 
 
 
-fn read_header(stream:&mut Socket, on_websocket: fn(WebSocketParser, Settings), on_request: fn(Request, Settings), user_data: Settings, stopped_clone: &Arc<AtomicBool>) -> bool {
+fn read_header(stream:&mut Socket, websockets: &WebSocketRoutes, on_request: fn(Request, Settings), user_data: Settings, stopped_clone: &Arc<AtomicBool>, access_log: &Option<Arc<Mutex<AccessLog>>>) -> bool {
     let mut buffer = [0; 1];
     let mut request = String::new();
     
@@ -712,29 +950,105 @@ fn read_header(stream:&mut Socket, on_websocket: fn(WebSocketParser, Settings),
         return false;
     }
     let mut req = Request::new(stream, request.clone());
+    req.access_log = access_log.clone();
+    req.live_reload = user_data.live_reload;
     if req.get_header("upgrade").to_lowercase() == "websocket" {
+        if let Some(check) = websockets.upgrade_check() {
+            // A refused upgrade request can't be followed by another request on this connection
+            req.set_header("Connection", "close");
+            let Some(checked) = check(req, user_data) else { return false; };
+            req = checked;
+        }
+        let Some(route) = websockets.find(&req.path) else {
+            refuse_upgrade(req, 404, "no WebSocket endpoint at this path");
+            return false;
+        };
+        let route_protocols = route.protocols.iter().map(|protocol| protocol.as_str()).collect::<Vec<_>>();
+        let protocols = if route_protocols.is_empty() { user_data.websocket_protocols } else { &route_protocols };
+        let protocol = match wsparser::check_handshake(&req, protocols, user_data.websocket_origins) {
+            Ok(protocol) => protocol,
+            Err((status, reason)) => {
+                refuse_upgrade(req, status, reason);
+                return false;
+            }
+        };
+        req.status_code = 101;
+        req.record_completion();
         let mut ws = WebSocketParser::new(req);
+        ws.protocol = protocol;
+        if user_data.websocket_compression {
+            ws.allow_compression(user_data.websocket_no_context_takeover);
+        }
         ws.do_handshake(request);
-        (on_websocket)(ws, user_data);
+        ws.set_keepalive(Duration::from_secs(user_data.websocket_ping_interval), Duration::from_secs(user_data.websocket_idle_timeout));
+        let _session = metrics::track_websocket();
+        (route.handler)(ws, user_data);
+        // The connection belongs to the WebSocket now and has been closed when the parser dropped
+        return false;
     } else {
         (on_request)(req, user_data);
     }
     true
 }
 
+// Answers an upgrade request we won't switch protocols for; the connection is closed afterwards
+fn refuse_upgrade(mut req: Request, status: i32, reason: &str) {
+    logging::debug(format!("WebSocket handshake refused: {}", reason));
+    req.set_status(status);
+    if status == 426 {
+        req.set_header("Sec-WebSocket-Version", "13");
+    }
+    req.set_header("Connection", "close");
+    req.set_header("Content-Type", "text/plain");
+    req.set_header("Content-Length", &(reason.len() + 1).to_string());
+    req.write_string(&format!("{}\n", reason));
+    req.end();
+}
+
+// Answers a request for the metrics path with the current metrics in Prometheus text format
+pub fn serve_metrics(mut req: Request, _opts: Settings) {
+    if req.method != "GET" && req.method != "HEAD" {
+        req.set_status(405);
+        req.set_header("Allow", "GET, HEAD");
+        req.set_header("Content-Length", "0");
+        req.end();
+        return;
+    }
+    let body = metrics::render();
+    req.set_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8");
+    req.set_header("Content-Length", &body.len().to_string());
+    if req.method != "HEAD" {
+        req.write(body.as_bytes());
+    }
+    req.end();
+}
+
+// Handlers for the separate metrics port, which serves nothing else
+fn metrics_only(mut req: Request, opts: Settings) {
+    if req.path == opts.metrics_path {
+        serve_metrics(req, opts);
+        return;
+    }
+    req.set_status(404);
+    req.set_header("Content-Length", "0");
+    req.end();
+}
+
 #[allow(dead_code)]
 pub struct Server {
     opts: Settings<'static>,
     sender: Option<mpsc::Sender<String>>,
     receiver: Arc<Mutex<mpsc::Receiver<String>>>,
     running: bool,
+    local_addrs: Vec<ListenAddr>,
+    access_log: Option<Arc<Mutex<AccessLog>>>,
     on_request: fn(Request, Settings),
-    on_websocket: fn(WebSocketParser, Settings)
+    websockets: WebSocketRoutes
 }
 
 #[allow(dead_code)]
 impl Server {
-    pub fn new(opts: Settings<'static>, on_request: fn(Request, Settings), on_websocket: fn(WebSocketParser, Settings)) -> Server {
+    pub fn new(opts: Settings<'static>, on_request: fn(Request, Settings)) -> Server {
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
         Server {
@@ -742,74 +1056,180 @@ impl Server {
             receiver,
             sender: Some(sender),
             running: false,
+            local_addrs: Vec::new(),
+            access_log: None,
             on_request,
-            on_websocket
+            websockets: WebSocketRoutes::new()
         }
     }
-    pub fn start(&mut self) -> bool {
-        let receiver = self.receiver.clone();
+    // Register WebSocket endpoints here before calling start
+    pub fn websocket_routes(&mut self) -> &mut WebSocketRoutes {
+        &mut self.websockets
+    }
+    // The configured TCP listeners, or a single one derived from bind/local_network/ipv6/port/https
+    // (none when only listening on a Unix domain socket)
+    pub fn listeners(&self) -> Vec<Listener<'static>> {
         let opts = self.opts;
-        let host = if opts.local_network {
+        if !opts.listeners.is_empty() {
+            return opts.listeners.to_vec();
+        }
+        if !opts.unix_socket.is_empty() {
+            return Vec::new();
+        }
+        let host = if !opts.bind.is_empty() {
+            opts.bind
+        } else if opts.local_network {
             if opts.ipv6 { "::" } else { "0.0.0.0" }
         } else if opts.ipv6 { "::1" } else { "127.0.0.1" };
-        let port = opts.port;
-        let on_request = self.on_request;
-        let on_websocket = self.on_websocket;
-        match TcpListener::bind(format!("{}:{}", host, port)) {
-            Ok(listener) => {
-                match listener.set_nonblocking(true) {
-                    Ok(_) => {},
-                    Err(_) => { return false; },
+        vec![Listener {
+            host,
+            port: opts.port,
+            https: opts.https
+        }]
+    }
+    // Bind everything first so a failure doesn't leave the server half started
+    fn bind_listeners(&self) -> Result<Vec<(BoundListener, bool, ListenAddr)>, Error> {
+        let opts = self.opts;
+        let mut bound = Vec::new();
+        for config in self.listeners() {
+            let scheme = if config.https { "https" } else { "http" };
+            let Some(mut addr) = resolve_listen_addr(config.host, config.port) else {
+                return Err(Error::Config(format!("invalid listen address {}://{}:{}/", scheme, config.host, config.port)));
+            };
+            let mut attempts = 0;
+            let listener = loop {
+                match bind_tcp(addr) {
+                    Ok(listener) => break listener,
+                    Err(err) if err.kind() == std::io::ErrorKind::AddrInUse && addr.port() != 0 && addr.port() < u16::MAX && attempts < opts.port_auto_increment => {
+                        attempts += 1;
+                        addr.set_port(addr.port() + 1);
+                    }
+                    Err(source) => return Err(Error::Bind { addr: format!("{}://{}/", scheme, addr), source })
                 }
-                thread::spawn(move || {
-                    let stopped: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-                    
-                    println!("Server started on http{}://{}:{}/", if opts.https { "s" } else { "" }, host, port);
-
-                    let mut handler = SocketHandler::new(opts.https, opts.https_cert, opts.https_key);
-
-                    for stream in listener.incoming() {
-                        match stream {
-                            Ok(stream) => {
-                                let stopped_clone = Arc::clone(&stopped);
-                                handler.execute(stream, move |mut socket| {
-                                    while read_header(&mut socket, on_websocket, on_request, opts, &stopped_clone) {
-                                        // keep alive
-                                    }
-                                    socket.drop();
-                                    drop(stopped_clone);
-                                });
-                            }
-                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                let Ok(handler) = receiver.lock() else {
+            };
+            // With port 0 only the OS knows which port was picked
+            let addr = listener.local_addr().unwrap_or(addr);
+            bound.push((BoundListener::Tcp(listener), config.https, ListenAddr::Tcp { addr, https: config.https }));
+        }
+        if !opts.unix_socket.is_empty() {
+            #[cfg(unix)]
+            match listener::bind_unix(opts.unix_socket, opts.unix_socket_mode) {
+                Ok(listener) => bound.push((BoundListener::Unix(listener, opts.unix_socket.to_string()), false, ListenAddr::Unix(opts.unix_socket.to_string()))),
+                Err(source) => return Err(Error::Bind { addr: format!("unix:{}", opts.unix_socket), source })
+            }
+            #[cfg(not(unix))]
+            return Err(Error::Config(String::from("Unix domain sockets are not supported on this platform")));
+        }
+        Ok(bound)
+    }
+    // The metrics port listens on the same host as the first regular listener
+    fn bind_metrics_listener(&self) -> Result<(BoundListener, ListenAddr), Error> {
+        let host = self.listeners().first().map(|listener| listener.host).unwrap_or("127.0.0.1");
+        let Some(addr) = resolve_listen_addr(host, self.opts.metrics_port) else {
+            return Err(Error::Config(format!("invalid metrics address {}:{}", host, self.opts.metrics_port)));
+        };
+        let listener = bind_tcp(addr).map_err(|source| Error::Bind { addr: format!("http://{}/", addr), source })?;
+        let addr = listener.local_addr().unwrap_or(addr);
+        Ok((BoundListener::Tcp(listener), ListenAddr::Tcp { addr, https: false }))
+    }
+    // Every address the server is listening on, in the order of Server::listeners followed by the
+    // metrics port (empty until started)
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.local_addrs
+    }
+    // Returns the first address the server is listening on
+    pub fn start(&mut self) -> Result<ListenAddr, Error> {
+        let opts = self.opts;
+        let on_request = self.on_request;
+        if opts.live_reload && self.websockets.find(live_reload::LIVE_RELOAD_PATH).is_none() {
+            live_reload::watch(opts.path)?;
+            self.websockets.add(live_reload::LIVE_RELOAD_PATH, live_reload::on_websocket);
+        }
+        let filter = IpFilter::new(opts.ip_allowlist, opts.ip_denylist).map_err(Error::Config)?;
+        if !opts.access_log.is_empty() && self.access_log.is_none() {
+            let access_log = AccessLog::open(opts.access_log, opts.access_log_format, opts.access_log_max_size, opts.access_log_max_files)?;
+            self.access_log = Some(Arc::new(Mutex::new(access_log)));
+        }
+        #[cfg(unix)]
+        let inherited = systemd::listen_fds(opts.systemd_listen_fds);
+        #[cfg(not(unix))]
+        let inherited = Vec::new();
+        let bound = if inherited.is_empty() {
+            self.bind_listeners()?
+        } else {
+            inherited
+        };
+        // Set up TLS before spawning anything so a bad certificate fails the whole start
+        let mut handlers = Vec::new();
+        for (listener, https, addr) in bound {
+            handlers.push((listener, SocketHandler::new(https, opts.https_cert, opts.https_key)?, addr, on_request, self.websockets.clone()));
+        }
+        if !opts.metrics_path.is_empty() && opts.metrics_port != 0 {
+            let (listener, addr) = self.bind_metrics_listener()?;
+            handlers.push((listener, SocketHandler::new(false, "", "")?, addr, metrics_only, WebSocketRoutes::new()));
+        }
+        self.local_addrs = handlers.iter().map(|(_, _, addr, _, _)| addr.clone()).collect();
+        let stopped: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        for (listener, mut handler, addr, on_request, websockets) in handlers {
+            let receiver = self.receiver.clone();
+            let filter = filter.clone();
+            let stopped = Arc::clone(&stopped);
+            let access_log = self.access_log.clone();
+            thread::spawn(move || {
+                logging::info(format!("Server started on {}", addr));
+
+                loop {
+                    match listener.accept() {
+                        Ok(stream) => {
+                            // Refused clients are disconnected before TLS or any HTTP parsing
+                            if let Incoming::Tcp(ref tcp) = stream {
+                                if !tcp.peer_addr().is_ok_and(|addr| filter.is_allowed(addr.ip())) {
+                                    drop(stream);
                                     continue;
-                                };
-                                let message = handler.try_recv();
-                                if let Ok(job) = message {
-                                    if job == *"kill" {
-                                        stopped.store(true, Ordering::Relaxed);
-                                        break;
-                                    }
                                 }
-                                thread::sleep(Duration::from_millis(10));
                             }
-                            Err(e) => panic!("encountered IO error: {}", e),
+                            let stopped_clone = Arc::clone(&stopped);
+                            let access_log = access_log.clone();
+                            let websockets = websockets.clone();
+                            handler.execute(stream, move |mut socket| {
+                                let _connection = metrics::track_connection();
+                                while read_header(&mut socket, &websockets, on_request, opts, &stopped_clone, &access_log) {
+                                    // keep alive
+                                }
+                                socket.drop();
+                                drop(stopped_clone);
+                            });
                         }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            // Whichever listener receives the kill message stops the others through `stopped`
+                            if stopped.load(Ordering::Relaxed) { break; };
+                            let Ok(handler) = receiver.lock() else {
+                                continue;
+                            };
+                            let message = handler.try_recv();
+                            if let Ok(job) = message {
+                                if job == *"kill" {
+                                    stopped.store(true, Ordering::Relaxed);
+                                    break;
+                                }
+                            }
+                            drop(handler);
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                        Err(e) => panic!("encountered IO error: {}", e),
                     }
-                    drop(listener);
-                });
-            },
-            Err(_) => {
-                println!("Failed to listen on http://{}:{}/", host, port);
-                return false;
-            },
+                }
+                listener.close();
+            });
         }
         self.running = true;
-        true
+        systemd::notify("READY=1");
+        self.local_addrs.first().cloned().ok_or_else(|| Error::Config(String::from("no listeners configured")))
     }
     pub fn terminate(&mut self) {
         if !self.running { return; };
-        println!("Killing server");
+        logging::info("Killing server");
+        systemd::notify("STOPPING=1");
         self.running = false;
         let Some(sender) = self.sender.as_ref() else {
             self.terminate_failed(0);
@@ -819,12 +1239,12 @@ impl Server {
             self.terminate_failed(0);
             return;
         };
-        println!("Server has been killed");
+        logging::info("Server has been killed");
     }
     pub fn terminate_failed(&mut self, count: i32) {
-        println!("Failed to kill server. Retrying...");
+        logging::warn("Failed to kill server. Retrying...");
         if !self.running { return; };
-        println!("Killing server");
+        logging::info("Killing server");
         self.running = false;
         let Some(sender) = self.sender.as_ref() else {
             self.terminate_failed(count + 1);
@@ -834,6 +1254,6 @@ impl Server {
             self.terminate_failed(count + 1);
             return;
         };
-        println!("Server has been killed");
+        logging::info("Server has been killed");
     }
 }
@@ -0,0 +1,165 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering}
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+// Upper bounds (in seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// Anything else is counted as "OTHER" so clients can't blow up the number of series
+const METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+// Seconds of per-second request counts kept for recent_requests
+const RECENT_WINDOW: usize = 300;
+
+struct RequestStats {
+    by_method_status: BTreeMap<(&'static str, i32), u64>,
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    latency_count: u64,
+    // (unix second, requests finished in that second), indexed by second % RECENT_WINDOW
+    recent: [(u64, u64); RECENT_WINDOW]
+}
+
+static REQUESTS: Mutex<RequestStats> = Mutex::new(RequestStats {
+    by_method_status: BTreeMap::new(),
+    latency_buckets: [0; LATENCY_BUCKETS.len()],
+    latency_sum: 0.0,
+    latency_count: 0,
+    recent: [(0, 0); RECENT_WINDOW]
+});
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+static WEBSOCKETS: AtomicI64 = AtomicI64::new(0);
+static TLS_HANDSHAKE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_request(method: &str, status: i32, bytes: u64, duration: Duration) {
+    let method = METHODS.iter().find(|known| **known == method).copied().unwrap_or("OTHER");
+    BYTES_SENT.fetch_add(bytes, Ordering::Relaxed);
+    let Ok(mut stats) = REQUESTS.lock() else { return; };
+    *stats.by_method_status.entry((method, status)).or_insert(0) += 1;
+    let seconds = duration.as_secs_f64();
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+        stats.latency_buckets[bucket] += 1;
+    }
+    stats.latency_sum += seconds;
+    stats.latency_count += 1;
+    let now = unix_now();
+    let slot = &mut stats.recent[now as usize % RECENT_WINDOW];
+    if slot.0 != now {
+        *slot = (now, 0);
+    }
+    slot.1 += 1;
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|dur| dur.as_secs()).unwrap_or(0)
+}
+
+pub fn record_tls_handshake_failure() {
+    TLS_HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+// Counts as in flight until the returned guard is dropped
+pub fn track_connection() -> Gauge {
+    Gauge::new(&CONNECTIONS)
+}
+
+pub fn track_websocket() -> Gauge {
+    Gauge::new(&WEBSOCKETS)
+}
+
+pub struct Gauge {
+    value: &'static AtomicI64
+}
+
+impl Gauge {
+    fn new(value: &'static AtomicI64) -> Gauge {
+        value.fetch_add(1, Ordering::Relaxed);
+        Gauge {
+            value
+        }
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn connections_in_flight() -> i64 {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+pub fn websocket_sessions() -> i64 {
+    WEBSOCKETS.load(Ordering::Relaxed)
+}
+
+pub fn requests_total() -> u64 {
+    REQUESTS.lock().map(|stats| stats.latency_count).unwrap_or(0)
+}
+
+pub fn bytes_sent_total() -> u64 {
+    BYTES_SENT.load(Ordering::Relaxed)
+}
+
+// Requests finished within the last `seconds` (at most 300)
+pub fn recent_requests(seconds: u64) -> u64 {
+    let now = unix_now();
+    let Ok(stats) = REQUESTS.lock() else { return 0; };
+    stats.recent.iter()
+        .filter(|(second, _)| *second <= now && now - *second < seconds)
+        .map(|(_, count)| count)
+        .sum()
+}
+
+// Completed requests by status class: 1xx, 2xx, 3xx, 4xx, 5xx
+pub fn requests_by_status_class() -> [u64; 5] {
+    let mut classes = [0; 5];
+    let Ok(stats) = REQUESTS.lock() else { return classes; };
+    for ((_, status), count) in stats.by_method_status.iter() {
+        if (100..600).contains(status) {
+            classes[(*status / 100 - 1) as usize] += count;
+        }
+    }
+    classes
+}
+
+// Prometheus text exposition format (version 0.0.4)
+pub fn render() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP http_requests_total Completed HTTP requests.");
+    let _ = writeln!(out, "# TYPE http_requests_total counter");
+    let Ok(stats) = REQUESTS.lock() else { return out; };
+    for ((method, status), count) in stats.by_method_status.iter() {
+        let _ = writeln!(out, "http_requests_total{{method=\"{}\",status=\"{}\"}} {}", method, status, count);
+    }
+    let _ = writeln!(out, "# HELP http_request_duration_seconds Time from receiving the request head to finishing the response.");
+    let _ = writeln!(out, "# TYPE http_request_duration_seconds histogram");
+    let mut cumulative = 0;
+    for (le, count) in LATENCY_BUCKETS.iter().zip(stats.latency_buckets.iter()) {
+        cumulative += count;
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+    }
+    let _ = writeln!(out, "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", stats.latency_count);
+    let _ = writeln!(out, "http_request_duration_seconds_sum {}", stats.latency_sum);
+    let _ = writeln!(out, "http_request_duration_seconds_count {}", stats.latency_count);
+    drop(stats);
+    let _ = writeln!(out, "# HELP http_response_bytes_total Response body bytes sent.");
+    let _ = writeln!(out, "# TYPE http_response_bytes_total counter");
+    let _ = writeln!(out, "http_response_bytes_total {}", bytes_sent_total());
+    let _ = writeln!(out, "# HELP http_connections_in_flight Open client connections.");
+    let _ = writeln!(out, "# TYPE http_connections_in_flight gauge");
+    let _ = writeln!(out, "http_connections_in_flight {}", connections_in_flight());
+    let _ = writeln!(out, "# HELP websocket_sessions_active Open WebSocket sessions.");
+    let _ = writeln!(out, "# TYPE websocket_sessions_active gauge");
+    let _ = writeln!(out, "websocket_sessions_active {}", websocket_sessions());
+    let _ = writeln!(out, "# HELP tls_handshake_failures_total TLS handshakes that failed before a request was read.");
+    let _ = writeln!(out, "# TYPE tls_handshake_failures_total counter");
+    let _ = writeln!(out, "tls_handshake_failures_total {}", TLS_HANDSHAKE_FAILURES.load(Ordering::Relaxed));
+    out
+}
@@ -1,6 +1,14 @@
-use openssl::ssl::SslStream;
+use openssl::ssl::{ErrorCode, SslStream};
+#[cfg(unix)]
 use std::{
-    net::TcpStream,
+    mem::MaybeUninit,
+    os::unix::net::UnixStream
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+    net::{TcpStream, SocketAddr, Shutdown},
+    sync::{Arc, Mutex, MutexGuard},
     io,
     io::{
         Read,
@@ -10,61 +18,245 @@ use std::{
     }
 };
 
+// Writes through a SocketWriter fail after stalling this long, so a peer that stops reading can't
+// hold the writer (and everyone waiting on it) forever
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// The longest a TLS read holds the lock while waiting for the rest of a record
+const TLS_READ_SLICE: Duration = Duration::from_millis(50);
+
+enum Stream {
+    Tcp(TcpStream),
+    // Locked so a SocketWriter can write while this side reads
+    Tls(Arc<Mutex<SslStream<TcpStream>>>),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
 pub struct Socket {
-    stream: Result<TcpStream, SslStream<TcpStream>>
+    stream: Stream,
+    // Second handle to a TLS connection that has a SocketWriter. Reads wait on it for input
+    // without holding the lock, so the writer isn't held up while the peer is quiet.
+    tls_wait: Option<TcpStream>
+}
+
+// The write side of a Socket, for writing from another thread while the Socket is being read.
+// Callers must make sure writes from both don't overlap.
+pub struct SocketWriter {
+    stream: WriterStream
+}
+
+enum WriterStream {
+    Tcp(TcpStream),
+    Tls(Arc<Mutex<SslStream<TcpStream>>>, TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+fn lock(stream: &Mutex<SslStream<TcpStream>>) -> io::Result<MutexGuard<'_, SslStream<TcpStream>>> {
+    stream.lock().map_err(|_| Error::other("TLS stream lock poisoned"))
+}
+
+// Returns false at the end of the stream. Only waits once the connection has a SocketWriter.
+fn wait_for_tls_input(stream: &Mutex<SslStream<TcpStream>>, raw: &Option<TcpStream>) -> io::Result<bool> {
+    let Some(raw) = raw else { return Ok(true); };
+    // Data OpenSSL already decrypted doesn't show up on the TCP socket
+    if lock(stream)?.ssl().pending() > 0 {
+        return Ok(true);
+    }
+    Ok(raw.peek(&mut [0])? > 0)
+}
+
+// Once the connection has a SocketWriter, input showing up on the TCP socket may only be part of a
+// record, or a message with no application data in it, like a session ticket or a key update.
+// So reads run under the lock in short slices and let the writer in between them.
+fn read_tls(stream: &Mutex<SslStream<TcpStream>>, raw: &Option<TcpStream>, mut read: impl FnMut(&mut SslStream<TcpStream>) -> io::Result<usize>) -> io::Result<usize> {
+    let Some(ref raw_stream) = raw else { return read(&mut *lock(stream)?); };
+    // The caller's read timeout, which only applies to the read as a whole
+    let timeout = raw_stream.read_timeout()?;
+    let started = Instant::now();
+    loop {
+        if !wait_for_tls_input(stream, raw)? {
+            return Ok(0);
+        }
+        let result = {
+            let mut stream = lock(stream)?;
+            raw_stream.set_read_timeout(Some(TLS_READ_SLICE))?;
+            let result = read(&mut stream);
+            raw_stream.set_read_timeout(timeout)?;
+            result
+        };
+        match result {
+            Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if let Some(timeout) = timeout {
+                    if started.elapsed() >= timeout { return result; };
+                }
+                // Gives a writer waiting on the lock its turn
+                thread::sleep(Duration::from_millis(1));
+            }
+            result => return result
+        }
+    }
 }
 
 impl Socket {
-    pub fn new(stream: Result<TcpStream, SslStream<TcpStream>>) -> Socket {
+    pub fn tcp(stream: TcpStream) -> Socket {
         Socket {
-            stream
+            stream: Stream::Tcp(stream),
+            tls_wait: None
+        }
+    }
+    pub fn tls(stream: SslStream<TcpStream>) -> Socket {
+        Socket {
+            stream: Stream::Tls(Arc::new(Mutex::new(stream))),
+            tls_wait: None
+        }
+    }
+    #[cfg(unix)]
+    pub fn unix(stream: UnixStream) -> Socket {
+        Socket {
+            stream: Stream::Unix(stream),
+            tls_wait: None
         }
     }
+    pub fn is_tls(&self) -> bool {
+        matches!(self.stream, Stream::Tls(_))
+    }
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.stream {
-            Ok(ref mut stream) => {
+            Stream::Tcp(ref mut stream) => {
                 stream.read(buf)
             }
-            Err(ref mut stream) => {
+            Stream::Tls(ref stream) => {
+                read_tls(stream, &self.tls_wait, |stream| stream.read(buf))
+            }
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => {
                 stream.read(buf)
             }
         }
     }
     pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
         match self.stream {
-            Ok(ref mut stream) => {
+            Stream::Tcp(ref mut stream) => {
                 stream.write_all(buf)
             }
-            Err(ref mut stream) => {
+            Stream::Tls(ref stream) => {
+                lock(stream)?.write_all(buf)
+            }
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => {
                 stream.write_all(buf)
             }
         }
     }
     pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self.stream {
-            Ok(ref mut stream) => {
+            Stream::Tcp(ref mut stream) => {
                 stream.peek(buf)
             }
-            Err(ref mut stream) => {
-                match stream.ssl_peek(buf) {
+            Stream::Tls(ref stream) => {
+                // Mapped like SslStream::read does, so a read timeout still shows up as WouldBlock/TimedOut
+                read_tls(stream, &self.tls_wait, |stream| match stream.ssl_peek(buf) {
                     Ok(e) => {Ok(e)},
-                    Err(_) => {Err(Error::new(ErrorKind::Other, "oh no!"))}
-                }
+                    Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => Ok(0),
+                    Err(ref e) if e.code() == ErrorCode::SYSCALL && e.io_error().is_none() => Ok(0),
+                    Err(e) => Err(e.into_io_error().unwrap_or_else(Error::other))
+                })
             }
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => {
+                // UnixStream::peek is still unstable, so go through MSG_PEEK directly
+                // SAFETY: initialized bytes are valid MaybeUninit<u8>, and recv only ever writes to them
+                let uninit = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+                socket2::SockRef::from(&*stream).peek(uninit)
+            }
+        }
+    }
+    // Reads that wait longer than this fail with WouldBlock/TimedOut instead of blocking forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(ref stream) => lock(stream)?.get_ref().set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.set_read_timeout(timeout)
+        }
+    }
+    // None for Unix domain sockets
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.stream {
+            Stream::Tcp(ref stream) => stream.peer_addr().ok(),
+            Stream::Tls(ref stream) => lock(stream).ok()?.get_ref().peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None
         }
     }
     pub fn shutdown(&mut self) {
         match self.stream {
-            Ok(ref mut stream) => {
-                let _ = stream.shutdown(std::net::Shutdown::Both);
+            Stream::Tcp(ref mut stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Stream::Tls(ref stream) => {
+                // A writer stuck on a slow peer holds the lock; shutting down the TCP side below frees it
+                if let Ok(mut stream) = stream.try_lock() {
+                    let _ = stream.shutdown();
+                }
+                if let Some(ref raw) = self.tls_wait {
+                    let _ = raw.shutdown(Shutdown::Both);
+                }
             }
-            Err(ref mut stream) => {
-                let _ = stream.shutdown();
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
             }
         }
     }
+    pub fn writer(&mut self) -> io::Result<SocketWriter> {
+        // The timeout is a socket option, so it applies to writes through the Socket from here on too
+        let stream = match self.stream {
+            Stream::Tcp(ref stream) => {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                WriterStream::Tcp(stream.try_clone()?)
+            }
+            Stream::Tls(ref stream) => {
+                let raw = lock(stream)?.get_ref().try_clone()?;
+                raw.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                if self.tls_wait.is_none() {
+                    self.tls_wait = Some(raw.try_clone()?);
+                }
+                WriterStream::Tls(stream.clone(), raw)
+            }
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                WriterStream::Unix(stream.try_clone()?)
+            }
+        };
+        Ok(SocketWriter {
+            stream
+        })
+    }
     pub fn drop(mut self) {
         self.shutdown();
         drop(self.stream);
     }
 }
+
+impl SocketWriter {
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.stream {
+            WriterStream::Tcp(ref mut stream) => stream.write_all(buf),
+            WriterStream::Tls(ref stream, _) => lock(stream)?.write_all(buf),
+            #[cfg(unix)]
+            WriterStream::Unix(ref mut stream) => stream.write_all(buf)
+        }
+    }
+    // Closes the connection in both directions, which also wakes up the reading side
+    pub fn shutdown(&self) {
+        let _ = match self.stream {
+            WriterStream::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            WriterStream::Tls(_, ref raw) => raw.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            WriterStream::Unix(ref stream) => stream.shutdown(Shutdown::Both)
+        };
+    }
+}
@@ -7,8 +7,12 @@ use openssl::{
     pkey::PKey
 };
 
-use std::net::TcpStream;
-use crate::Socket;
+use crate::{
+    Socket,
+    error::Error,
+    metrics,
+    listener::Incoming
+};
 
 fn to_acceptor(cert_str: &str, key_str: &str) -> Result<SslAcceptor, openssl::error::ErrorStack> {
     let cert_str = cert_str
@@ -39,38 +43,38 @@ fn to_acceptor(cert_str: &str, key_str: &str) -> Result<SslAcceptor, openssl::er
 
 pub struct SocketHandler {
     https: bool,
-    acceptor: Option<SslAcceptor>,
-    running: bool
+    acceptor: Option<SslAcceptor>
 }
 
 impl SocketHandler {
-    pub fn new(https: bool, https_cert: &str, https_key: &str) -> SocketHandler {
-        let mut running = true;
+    pub fn new(https: bool, https_cert: &str, https_key: &str) -> Result<SocketHandler, Error> {
         let acceptor = if https {
-            if let Ok(acc) = to_acceptor(https_cert, https_key) {
-                Some(acc)
-            } else {
-                println!("Failed to create ssl acceptor!");
-                running = false;
-                None
-            }
+            Some(to_acceptor(https_cert, https_key)?)
         } else {
             None
         };
-        SocketHandler {
+        Ok(SocketHandler {
             acceptor,
-            https,
-            running
-        }
+            https
+        })
     }
-    pub fn execute<F>(&mut self, stream: TcpStream, f: F)
+    pub fn execute<F>(&mut self, stream: Incoming, f: F)
     where
         F: FnOnce(Socket) + Send + 'static,
     {
-        if !self.running { return; };
+        let stream = match stream {
+            Incoming::Tcp(stream) => stream,
+            #[cfg(unix)]
+            Incoming::Unix(stream) => {
+                thread::spawn(move || {
+                    f(Socket::unix(stream));
+                });
+                return;
+            }
+        };
         if !self.https {
             thread::spawn(move || {
-                f(Socket::new(Ok(stream)));
+                f(Socket::tcp(stream));
             });
             return;
         }
@@ -78,13 +82,13 @@ impl SocketHandler {
         thread::spawn(move || {
             match acceptor.accept(stream) {
                 Ok(stream) => {
-                    f(Socket::new(Err(stream)));
+                    f(Socket::tls(stream));
                 }
                 Err (ref _e) => {
                     //99% of the time this is an ssl handshake error. We should be able to safely ignore this.
+                    metrics::record_tls_handshake_failure();
                 }
             }
         });
     }
 }
-
@@ -0,0 +1,183 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::Duration
+};
+
+// Send a comment at least this often on an otherwise quiet stream, so proxies don't time it out
+// and a client that went away is noticed
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+// One Server-Sent Event (https://html.spec.whatwg.org/multipage/server-sent-events.html)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+    data: String
+}
+
+// Field values end at a line break, and a NUL in the id makes browsers ignore it
+fn single_line(value: &str) -> String {
+    value.chars().filter(|c| !matches!(c, '\r' | '\n' | '\0')).collect()
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            event: None,
+            id: None,
+            retry: None,
+            data: data.to_string()
+        }
+    }
+    // The event type, for addEventListener on the client (default "message")
+    pub fn event(mut self, event: &str) -> Event {
+        self.event = Some(single_line(event));
+        self
+    }
+    // Sent back by the browser as Last-Event-ID when it reconnects
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+    // How long the browser waits before reconnecting, in milliseconds
+    pub fn retry(mut self, milliseconds: u64) -> Event {
+        self.retry = Some(milliseconds);
+        self
+    }
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+    // The event in wire format, including the blank line that dispatches it
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out += &format!("event: {}\n", event);
+        }
+        if let Some(id) = &self.id {
+            out += &format!("id: {}\n", id);
+        }
+        if let Some(retry) = self.retry {
+            out += &format!("retry: {}\n", retry);
+        }
+        // Multi-line data becomes one data field per line; the client joins them with \n again
+        for line in self.data.replace("\r\n", "\n").replace('\r', "\n").split('\n') {
+            out += &format!("data: {}\n", line);
+        }
+        out.push('\n');
+        out
+    }
+}
+
+// A comment line; clients ignore it, which makes it a cheap heartbeat
+pub fn comment(text: &str) -> String {
+    format!(": {}\n\n", single_line(text))
+}
+
+// The most recent events of a feed, numbered so reconnecting clients can pick up where they left
+// off: share one between the producer (push) and the request handlers (since).
+pub struct EventLog {
+    capacity: usize,
+    // Next id to hand out and the retained events, oldest first
+    events: Mutex<(u64, VecDeque<Event>)>
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        EventLog {
+            capacity,
+            events: Mutex::new((1, VecDeque::new()))
+        }
+    }
+    // Numbers the event (replacing any id it had) and returns it for sending to current listeners
+    pub fn push(&self, event: Event) -> Event {
+        let Ok(mut events) = self.events.lock() else { return event; };
+        let event = event.id(&events.0.to_string());
+        events.0 += 1;
+        events.1.push_back(event.clone());
+        while events.1.len() > self.capacity {
+            events.1.pop_front();
+        }
+        event
+    }
+    // The retained events after `last_event_id` (Request::last_event_id), or all of them for a new
+    // client. Everything retained is replayed as well for an id this log can't place: one from
+    // before a restart (ids start at 1 again), one older than what's retained, or not a number.
+    pub fn since(&self, last_event_id: Option<&str>) -> Vec<Event> {
+        let Ok(events) = self.events.lock() else { return Vec::new(); };
+        let (next, retained) = &*events;
+        // Ids are handed out consecutively, so the retained ones are oldest..next
+        let oldest = next - retained.len() as u64;
+        let skip = match last_event_id.and_then(|id| id.trim().parse::<u64>().ok()) {
+            Some(last) if last < *next && last + 1 >= oldest => (last + 1 - oldest) as usize,
+            _ => 0
+        };
+        retained.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_splits_multi_line_data() {
+        let event = Event::new("first\nsecond").event("update").id("7").retry(3000);
+        assert_eq!(event.encode(), "event: update\nid: 7\nretry: 3000\ndata: first\ndata: second\n\n");
+    }
+
+    #[test]
+    fn encode_treats_cr_as_line_break() {
+        assert_eq!(Event::new("a\r\nb\rc").encode(), "data: a\ndata: b\ndata: c\n\n");
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    #[test]
+    fn encode_keeps_fields_on_one_line() {
+        let event = Event::new("x").event("a\r\nb").id("1\n2");
+        assert_eq!(event.encode(), "event: ab\nid: 12\ndata: x\n\n");
+    }
+
+    fn log_with(count: usize, capacity: usize) -> EventLog {
+        let log = EventLog::new(capacity);
+        for i in 0..count {
+            log.push(Event::new(&i.to_string()));
+        }
+        log
+    }
+
+    fn ids(events: &[Event]) -> Vec<&str> {
+        events.iter().filter_map(|event| event.get_id()).collect()
+    }
+
+    #[test]
+    fn since_replays_after_known_id() {
+        let log = log_with(5, 10);
+        assert_eq!(ids(&log.since(Some("3"))), ["4", "5"]);
+        assert_eq!(ids(&log.since(Some(" 5 "))), Vec::<&str>::new());
+        assert_eq!(ids(&log.since(None)), ["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn since_replays_everything_for_unknown_ids() {
+        let log = log_with(5, 10);
+        // From before a restart, when the ids had got further
+        assert_eq!(ids(&log.since(Some("42"))), ["1", "2", "3", "4", "5"]);
+        assert_eq!(ids(&log.since(Some("abc"))), ["1", "2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn since_handles_evicted_events() {
+        let log = log_with(8, 3);
+        assert_eq!(ids(&log.since(None)), ["6", "7", "8"]);
+        // The client saw 5, which is gone, but nothing after it is missing
+        assert_eq!(ids(&log.since(Some("5"))), ["6", "7", "8"]);
+        assert_eq!(ids(&log.since(Some("6"))), ["7", "8"]);
+        // Events 3 and 4 are lost; send what's left
+        assert_eq!(ids(&log.since(Some("2"))), ["6", "7", "8"]);
+    }
+}
@@ -0,0 +1,103 @@
+#[cfg(unix)]
+use std::{
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::net::{UnixDatagram, UnixListener}
+    },
+    sync::atomic::{AtomicBool, Ordering}
+};
+use std::env;
+#[cfg(unix)]
+use socket2::Socket as RawSocket;
+#[cfg(unix)]
+use crate::{listener::BoundListener, ListenAddr, logging};
+
+// File descriptors passed by systemd start after stdin/stdout/stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+// The inherited fds are owned by whichever listeners adopt them first
+#[cfg(unix)]
+static ADOPTED: AtomicBool = AtomicBool::new(false);
+
+// Reads and clears LISTEN_PID/LISTEN_FDS/LISTEN_FDNAMES, returning (fd, https) for every socket systemd passed.
+// A socket named "https" (FileDescriptorName= in the .socket unit) is served over TLS.
+// Changing the environment is only sound while the process is single-threaded, so call this first thing in main.
+pub fn take_listen_fds() -> Vec<(i32, bool)> {
+    let mut fds = Vec::new();
+    let Ok(pid) = env::var("LISTEN_PID") else { return fds; };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return fds;
+    }
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let names = names.split(':').collect::<Vec<_>>();
+    // Child processes must not think the sockets are meant for them
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    #[cfg(unix)]
+    for i in 0..count.max(0) {
+        fds.push((LISTEN_FDS_START + i, names.get(i as usize) == Some(&"https")));
+    }
+    #[cfg(not(unix))]
+    let _ = (count, names);
+    fds
+}
+
+// Takes over the sockets returned by take_listen_fds; only the first call gets them
+#[cfg(unix)]
+pub(crate) fn listen_fds(fds: &[(i32, bool)]) -> Vec<(BoundListener, bool, ListenAddr)> {
+    let mut listeners = Vec::new();
+    if fds.is_empty() || ADOPTED.swap(true, Ordering::SeqCst) {
+        return listeners;
+    }
+    for &(fd, https) in fds {
+        // SAFETY: systemd hands ownership of the fds listed in LISTEN_FDS to this process, and ADOPTED keeps them from being taken twice
+        let socket = unsafe { RawSocket::from_raw_fd(fd) };
+        let _ = socket.set_cloexec(true);
+        let Ok(local) = socket.local_addr() else {
+            logging::warn(format!("Ignoring inherited fd {}: not a socket", fd));
+            continue;
+        };
+        if socket.set_nonblocking(true).is_err() { continue; };
+        if let Some(addr) = local.as_socket() {
+            listeners.push((BoundListener::Tcp(TcpListener::from(socket)), https, ListenAddr::Tcp { addr, https }));
+        } else if local.is_unix() {
+            let path = local.as_pathname().map(|path| path.display().to_string()).unwrap_or_default();
+            // An empty path keeps the socket file, which belongs to systemd, from being removed on shutdown
+            listeners.push((BoundListener::Unix(UnixListener::from(OwnedFd::from(socket)), String::new()), false, ListenAddr::Unix(path)));
+        } else {
+            logging::warn(format!("Ignoring inherited fd {}: unsupported socket type", fd));
+        }
+    }
+    listeners
+}
+
+// Sends a state update such as "READY=1" or "STOPPING=1" to the service manager, if there is one
+pub fn notify(state: &str) -> bool {
+    #[cfg(unix)]
+    {
+        let Ok(path) = env::var("NOTIFY_SOCKET") else { return false; };
+        let Ok(socket) = UnixDatagram::unbound() else { return false; };
+        if let Some(name) = path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let Ok(addr) = SocketAddr::from_abstract_name(name.as_bytes()) else { return false; };
+                return socket.send_to_addr(state.as_bytes(), &addr).is_ok();
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = name;
+                return false;
+            }
+        }
+        socket.send_to(state.as_bytes(), path).is_ok()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        false
+    }
+}
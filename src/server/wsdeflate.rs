@@ -0,0 +1,255 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+// Every message compressed with a sync flush ends in these bytes; RFC 7692 strips them on the wire
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Parameters agreed on in the handshake (RFC 7692 section 7.1)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool
+}
+
+impl DeflateParams {
+    // Picks the first permessage-deflate offer from the Sec-WebSocket-Extensions values that we can
+    // honour. `no_context_takeover` makes the server reset its compressor after every message even
+    // if the client didn't ask for it, trading compression ratio for memory.
+    pub fn negotiate(extensions: &[String], no_context_takeover: bool) -> Option<DeflateParams> {
+        extensions.iter()
+            .flat_map(|value| value.split(','))
+            .find_map(|offer| DeflateParams::accept(offer, no_context_takeover))
+    }
+    fn accept(offer: &str, no_context_takeover: bool) -> Option<DeflateParams> {
+        let mut parts = offer.split(';').map(|part| part.trim());
+        if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") { return None; };
+        let mut params = DeflateParams {
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: false
+        };
+        let mut seen = Vec::new();
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"'))),
+                None => (param.to_ascii_lowercase(), None)
+            };
+            // Declining malformed offers lets the client fall back to its next one
+            if seen.contains(&name) { return None; };
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                // We always inflate with the full 32KiB window, which covers any smaller client window
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) if window_bits(bits).is_some() => {}
+                // Our compressor only does 15 bits, so we can only agree to a limit of 15
+                ("server_max_window_bits", Some(bits)) if window_bits(bits) == Some(15) => {}
+                _ => return None
+            }
+            seen.push(name);
+        }
+        Some(params)
+    }
+    // The value of our Sec-WebSocket-Extensions response header
+    pub fn response(&self) -> String {
+        let mut response = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            response += "; server_no_context_takeover";
+        }
+        if self.client_no_context_takeover {
+            response += "; client_no_context_takeover";
+        }
+        response
+    }
+}
+
+fn window_bits(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+// Compresses outgoing messages. Kept apart from the Inflater because the two directions are used by
+// different threads once a connection has a WebSocketSender.
+pub struct Deflater {
+    params: DeflateParams,
+    compressor: Compress
+}
+
+pub struct Inflater {
+    params: DeflateParams,
+    decompressor: Decompress
+}
+
+impl Deflater {
+    pub fn new(params: DeflateParams) -> Deflater {
+        Deflater {
+            params,
+            compressor: Compress::new(Compression::default(), false)
+        }
+    }
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compressor.total_in();
+        loop {
+            let consumed = (self.compressor.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(64));
+            }
+            if self.compressor.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync).is_err() {
+                break;
+            }
+            // The flush is complete once all input is consumed and the output didn't fill up
+            if self.compressor.total_in() - start == data.len() as u64 && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&SYNC_TRAILER) {
+            out.truncate(out.len() - SYNC_TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compressor.reset();
+        }
+        out
+    }
+}
+
+impl Inflater {
+    pub fn new(params: DeflateParams) -> Inflater {
+        Inflater {
+            params,
+            decompressor: Decompress::new(false)
+        }
+    }
+    // Inflates one frame of a compressed message into `out`. `fin` marks the message's last frame.
+    // Fails if the data is corrupt or `out` would grow past `limit` bytes.
+    pub fn decompress(&mut self, data: &[u8], fin: bool, out: &mut Vec<u8>, limit: usize) -> Result<(), String> {
+        let mut input = data.to_vec();
+        if fin {
+            input.extend_from_slice(&SYNC_TRAILER);
+        }
+        let start = self.decompressor.total_in();
+        loop {
+            let consumed = (self.decompressor.total_in() - start) as usize;
+            if out.capacity() - out.len() < 1024 {
+                out.reserve(out.capacity().clamp(1024, limit.max(1024)));
+            }
+            let status = self.decompressor.decompress_vec(&input[consumed..], out, FlushDecompress::Sync)
+                .map_err(|err| err.to_string())?;
+            if out.len() > limit {
+                return Err(String::from("message too big"));
+            }
+            // A client may end the message with a final deflate block; the next message then starts a new stream
+            if status == Status::StreamEnd {
+                self.decompressor.reset(false);
+                break;
+            }
+            if self.decompressor.total_in() - start == input.len() as u64 && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if fin && self.params.client_no_context_takeover {
+            self.decompressor.reset(false);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(value: &str, no_context_takeover: bool) -> Option<DeflateParams> {
+        DeflateParams::negotiate(&[value.to_string()], no_context_takeover)
+    }
+
+    fn inflate(inflater: &mut Inflater, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        inflater.decompress(data, true, &mut out, 1 << 20).unwrap();
+        out
+    }
+
+    #[test]
+    fn negotiate_accepts_supported_offers() {
+        assert_eq!(offer("permessage-deflate", false), Some(DeflateParams::default()));
+        let params = offer("permessage-deflate; client_no_context_takeover; client_max_window_bits", false).unwrap();
+        assert!(params.client_no_context_takeover);
+        assert!(!params.server_no_context_takeover);
+        assert_eq!(params.response(), "permessage-deflate; client_no_context_takeover");
+        assert!(offer("permessage-deflate; server_max_window_bits=15; client_max_window_bits=\"10\"", false).is_some());
+        // Our own setting asks for the reset even if the client didn't
+        assert_eq!(offer("permessage-deflate", true).unwrap().response(), "permessage-deflate; server_no_context_takeover");
+    }
+
+    #[test]
+    fn negotiate_declines_malformed_or_unsupported_offers() {
+        assert_eq!(offer("permessage-deflate; server_no_context_takeover; server_no_context_takeover", false), None);
+        assert_eq!(offer("permessage-deflate; client_max_window_bits=9; client_max_window_bits=9", false), None);
+        assert_eq!(offer("permessage-deflate; server_max_window_bits=10", false), None);
+        assert_eq!(offer("permessage-deflate; server_max_window_bits", false), None);
+        assert_eq!(offer("permessage-deflate; client_max_window_bits=16", false), None);
+        assert_eq!(offer("permessage-deflate; server_no_context_takeover=1", false), None);
+        assert_eq!(offer("x-webkit-deflate-frame", false), None);
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_later_offers() {
+        let offers = ["permessage-deflate; server_max_window_bits=10, permessage-deflate; client_no_context_takeover".to_string()];
+        let params = DeflateParams::negotiate(&offers, false).unwrap();
+        assert!(params.client_no_context_takeover);
+    }
+
+    #[test]
+    fn compress_strips_sync_trailer() {
+        let mut deflater = Deflater::new(DeflateParams::default());
+        let data = deflater.compress(b"Hello");
+        assert!(!data.ends_with(&SYNC_TRAILER));
+        assert_eq!(inflate(&mut Inflater::new(DeflateParams::default()), &data), b"Hello");
+    }
+
+    #[test]
+    fn decompress_appends_sync_trailer_on_last_frame() {
+        // The compressed "Hello" from RFC 7692 section 7.2.3.1, without the trailer
+        let data = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let mut inflater = Inflater::new(DeflateParams::default());
+        assert_eq!(inflate(&mut inflater, &data), b"Hello");
+        // Split across frames, only the last one gets the trailer
+        let mut out = Vec::new();
+        inflater.decompress(&data[..3], false, &mut out, 1 << 20).unwrap();
+        inflater.decompress(&data[3..], true, &mut out, 1 << 20).unwrap();
+        assert_eq!(out, b"Hello");
+    }
+
+    #[test]
+    fn round_trip_shares_context_between_messages() {
+        let params = DeflateParams::default();
+        let (mut deflater, mut inflater) = (Deflater::new(params), Inflater::new(params));
+        let message = b"the same message twice, the same message twice".as_slice();
+        let first = deflater.compress(message);
+        let second = deflater.compress(message);
+        // The second copy refers back into the first
+        assert!(second.len() < first.len());
+        assert_eq!(inflate(&mut inflater, &first), message);
+        assert_eq!(inflate(&mut inflater, &second), message);
+    }
+
+    #[test]
+    fn round_trip_without_context_takeover() {
+        let params = DeflateParams { server_no_context_takeover: true, client_no_context_takeover: true };
+        let mut deflater = Deflater::new(params);
+        let message = b"the same message twice, the same message twice".as_slice();
+        let first = deflater.compress(message);
+        let second = deflater.compress(message);
+        assert_eq!(first, second);
+        // Each message inflates on its own, as a fresh inflater would see it
+        let mut inflater = Inflater::new(params);
+        assert_eq!(inflate(&mut inflater, &first), message);
+        assert_eq!(inflate(&mut inflater, &second), message);
+        assert_eq!(inflate(&mut Inflater::new(params), &second), message);
+    }
+
+    #[test]
+    fn decompress_enforces_limit() {
+        let mut deflater = Deflater::new(DeflateParams::default());
+        let data = deflater.compress(&[b'a'; 10000]);
+        let mut out = Vec::new();
+        let mut inflater = Inflater::new(DeflateParams::default());
+        assert!(inflater.decompress(&data, true, &mut out, 100).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock}
+};
+use crate::wsparser::{Message, WebSocketSender};
+
+// Named channels of WebSocket connections. Connections join with their WebSocketSender and anyone
+// holding the hub (handlers, server-side code) can broadcast to a channel. Broadcasting only
+// queues the message for each member, so it never waits for a slow client.
+pub struct Hub {
+    channels: Mutex<HashMap<String, Vec<WebSocketSender>>>
+}
+
+static GLOBAL: OnceLock<Hub> = OnceLock::new();
+
+// The hub shared by the whole process
+pub fn global() -> &'static Hub {
+    GLOBAL.get_or_init(Hub::new)
+}
+
+impl Default for Hub {
+    fn default() -> Hub {
+        Hub::new()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub {
+            channels: Mutex::new(HashMap::new())
+        }
+    }
+    pub fn join(&self, channel: &str, sender: &WebSocketSender) {
+        let Ok(mut channels) = self.channels.lock() else { return; };
+        let members = channels.entry(channel.to_string()).or_default();
+        if !members.contains(sender) {
+            members.push(sender.clone());
+        }
+    }
+    pub fn leave(&self, channel: &str, sender: &WebSocketSender) {
+        let Ok(mut channels) = self.channels.lock() else { return; };
+        if let Some(members) = channels.get_mut(channel) {
+            members.retain(|member| member != sender);
+            if members.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+    // Call when a connection ends; closed connections are also dropped lazily on the next broadcast
+    pub fn leave_all(&self, sender: &WebSocketSender) {
+        let Ok(mut channels) = self.channels.lock() else { return; };
+        for members in channels.values_mut() {
+            members.retain(|member| member != sender);
+        }
+        channels.retain(|_, members| !members.is_empty());
+    }
+    // Returns how many connections the message was queued for
+    pub fn broadcast(&self, channel: &str, message: &Message) -> usize {
+        self.send_to_channel(channel, message, None)
+    }
+    // Like broadcast, but skips `except`, typically the connection the message came from
+    pub fn broadcast_except(&self, channel: &str, message: &Message, except: &WebSocketSender) -> usize {
+        self.send_to_channel(channel, message, Some(except))
+    }
+    fn send_to_channel(&self, channel: &str, message: &Message, except: Option<&WebSocketSender>) -> usize {
+        let Ok(mut channels) = self.channels.lock() else { return 0; };
+        let Some(members) = channels.get_mut(channel) else { return 0; };
+        members.retain(|member| member.connected());
+        let sent = members.iter()
+            .filter(|member| Some(*member) != except)
+            .filter(|member| member.send(message.clone()))
+            .count();
+        if members.is_empty() {
+            channels.remove(channel);
+        }
+        sent
+    }
+    pub fn subscribers(&self, channel: &str) -> usize {
+        let Ok(channels) = self.channels.lock() else { return 0; };
+        channels.get(channel).map(|members| members.iter().filter(|member| member.connected()).count()).unwrap_or(0)
+    }
+    pub fn channels(&self) -> Vec<String> {
+        let Ok(channels) = self.channels.lock() else { return Vec::new(); };
+        let mut names = channels.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
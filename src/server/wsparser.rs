@@ -1,19 +1,280 @@
 use std::{
-    thread,
+    collections::VecDeque,
+    thread::{self, JoinHandle},
     str,
-    time::Duration
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering}
+    },
+    time::{Duration, Instant}
 };
 use sha1::{Sha1, Digest};
 use regex::Regex;
 use base64::{Engine as _, engine::{general_purpose}};
-use crate::{Request, Socket};
+use crate::{
+    Request,
+    Socket,
+    error::Error,
+    socket::{SocketWriter, WRITE_TIMEOUT},
+    wsdeflate::{DeflateParams, Deflater, Inflater}
+};
+
+const OP_CONTINUATION: u8 = 0;
+const OP_TEXT: u8 = 1;
+const OP_BINARY: u8 = 2;
+const OP_CLOSE: u8 = 8;
+const OP_PING: u8 = 9;
+const OP_PONG: u8 = 10;
+// Set on the first frame of a message compressed with permessage-deflate
+const RSV1: u8 = 0b01000000;
+// Messages shorter than this aren't worth compressing
+const MIN_COMPRESS_SIZE: usize = 64;
+
+// Reassembled messages larger than this are refused rather than buffered
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// Close status codes (RFC 6455 section 7.4.1)
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+// How long close() waits for the peer to answer the close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a closing connection waits for the writer thread to send what is queued
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// Read timeout used to wake up for keepalive pings and idle checks
+const KEEPALIVE_POLL: Duration = Duration::from_millis(500);
+// Messages a WebSocketSender may queue before the connection counts as too slow and is closed
+pub const MAX_QUEUED_MESSAGES: usize = 1024;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Codes a peer may send: the defined ones (minus those reserved for local use) plus the registered
+// and private ranges
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // Status code and reason, if the peer sent any
+    Close(Option<u16>, String)
+}
+
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: Vec<u8>
+}
+
+// Outbound queue shared between a connection's WebSocketSenders and its writer thread
+struct Outbox {
+    id: u64,
+    queue: Mutex<VecDeque<Message>>,
+    // Wakes the writer thread. Others set closed and overflowed with the queue locked so it sees them.
+    ready: Condvar,
+    closed: AtomicBool,
+    overflowed: AtomicBool
+}
+
+// A thread-safe handle for sending to a connection from anywhere, e.g. another connection's
+// handler or a broadcast (see wshub). Messages are queued and written by a writer thread of the
+// connection, so a slow client never blocks the sender. They may interleave with messages the
+// handler sends directly through the parser, but never in the middle of a frame.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    outbox: Arc<Outbox>
+}
+
+impl WebSocketSender {
+    // Unique for the lifetime of the process
+    pub fn id(&self) -> u64 {
+        self.outbox.id
+    }
+    pub fn connected(&self) -> bool {
+        !self.outbox.closed.load(Ordering::Relaxed)
+    }
+    // Returns false if the connection is gone or has MAX_QUEUED_MESSAGES waiting already, in which
+    // case it will be closed with 1008
+    pub fn send(&self, message: Message) -> bool {
+        if !self.connected() { return false; };
+        let Ok(mut queue) = self.outbox.queue.lock() else { return false; };
+        if queue.len() >= MAX_QUEUED_MESSAGES {
+            self.outbox.overflowed.store(true, Ordering::Relaxed);
+            self.outbox.ready.notify_one();
+            return false;
+        }
+        queue.push_back(message);
+        self.outbox.ready.notify_one();
+        true
+    }
+    pub fn send_text(&self, text: &str) -> bool {
+        self.send(Message::Text(text.to_string()))
+    }
+    // Starts the close handshake once everything queued before it has been sent
+    pub fn close(&self, code: u16, reason: &str) -> bool {
+        self.send(Message::Close(Some(code), reason.to_string()))
+    }
+}
+
+impl PartialEq for WebSocketSender {
+    fn eq(&self, other: &WebSocketSender) -> bool {
+        self.id() == other.id()
+    }
+}
 
-// WebSocketParser is not currently thread safe
+impl Eq for WebSocketSender {}
+
+// The sending side of a connection. The parser and its writer thread both write through it, so
+// frames never interleave and compressed messages share one compression context.
+struct WriteHalf {
+    // Set when sender() starts the writer thread; until then the parser writes to its own stream
+    socket: Option<SocketWriter>,
+    deflater: Option<Deflater>,
+    close_sent: bool,
+    // A write failed, so the connection is unusable
+    broken: bool
+}
+
+impl WriteHalf {
+    // `stream` is the parser's own stream, which is only used while there is no SocketWriter
+    fn write(&mut self, mut stream: Option<&mut Socket>, data: &[u8]) -> bool {
+        if self.broken { return false; };
+        let started = Instant::now();
+        loop {
+            let result = match (self.socket.as_mut(), stream.as_deref_mut()) {
+                (Some(socket), _) => socket.write(data),
+                (None, Some(stream)) => stream.write(data),
+                (None, None) => return false
+            };
+            match result {
+                Ok(()) => return true,
+                // A write that timed out may have sent part of the frame, so it can't be retried then
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && started.elapsed() < WRITE_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(_) => {
+                    self.broken = true;
+                    return false;
+                }
+            }
+        }
+    }
+    fn write_frame(&mut self, stream: Option<&mut Socket>, fin: bool, opcode: u8, payload: &[u8]) -> bool {
+        // Nothing but the close handshake may follow a close frame
+        if self.close_sent { return false; };
+        self.close_sent = opcode == OP_CLOSE;
+        let mut frame = frame_header(payload.len(), opcode as i32, fin);
+        frame.extend_from_slice(payload);
+        self.write(stream, &frame)
+    }
+    fn send_close(&mut self, stream: Option<&mut Socket>, code: Option<u16>, reason: &str) -> bool {
+        let mut payload = Vec::new();
+        if let Some(code) = code {
+            payload.extend_from_slice(&code.to_be_bytes());
+            // The whole close payload must fit in 125 bytes
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) { end -= 1; }
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        self.write_frame(stream, true, OP_CLOSE, &payload)
+    }
+    // The payload to send for a text or binary message and the RSV bits to go with it
+    fn compress(&mut self, data: &[u8]) -> (Option<Vec<u8>>, u8) {
+        match self.deflater.as_mut() {
+            Some(deflater) if data.len() >= MIN_COMPRESS_SIZE => (Some(deflater.compress(data)), RSV1),
+            _ => (None, 0)
+        }
+    }
+    // Sends a whole text or binary message, compressed if permessage-deflate was negotiated
+    fn send_data(&mut self, stream: Option<&mut Socket>, opcode: u8, data: &[u8]) -> bool {
+        match self.compress(data) {
+            (Some(payload), rsv) => self.write_frame(stream, true, opcode | rsv, &payload),
+            (None, _) => self.write_frame(stream, true, opcode, data)
+        }
+    }
+    fn send(&mut self, stream: Option<&mut Socket>, message: &Message) -> bool {
+        match message {
+            Message::Text(text) => self.send_data(stream, OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.send_data(stream, OP_BINARY, data),
+            Message::Ping(data) => self.write_frame(stream, true, OP_PING, data),
+            Message::Pong(data) => self.write_frame(stream, true, OP_PONG, data),
+            Message::Close(code, reason) => self.send_close(stream, *code, reason)
+        }
+    }
+    fn send_fragmented(&mut self, mut stream: Option<&mut Socket>, message: &Message, fragment_size: usize) -> bool {
+        let (opcode, data) = match message {
+            Message::Text(text) => (OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => (OP_BINARY, data.as_slice()),
+            _ => return self.send(stream, message)
+        };
+        // A compressed message is compressed as a whole and the result split into frames
+        let (compressed, rsv) = self.compress(data);
+        let data = compressed.as_deref().unwrap_or(data);
+        let chunks = data.chunks(fragment_size.max(1)).collect::<Vec<_>>();
+        if chunks.is_empty() {
+            return self.write_frame(stream, true, opcode, &[]);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let opcode = if i == 0 { opcode | rsv } else { OP_CONTINUATION };
+            if !self.write_frame(stream.as_deref_mut(), i == chunks.len() - 1, opcode, chunk) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Body of a connection's writer thread: sends what its WebSocketSenders queue until the parser is
+// dropped, the connection breaks or a close frame has gone out
+fn write_queued(outbox: Arc<Outbox>, writer: Arc<Mutex<WriteHalf>>) {
+    'messages: loop {
+        let Ok(mut queue) = outbox.queue.lock() else { break; };
+        while queue.is_empty() && !outbox.closed.load(Ordering::Relaxed) && !outbox.overflowed.load(Ordering::Relaxed) {
+            let Ok(woken) = outbox.ready.wait(queue) else { break 'messages; };
+            queue = woken;
+        }
+        let messages = queue.drain(..).collect::<Vec<_>>();
+        drop(queue);
+        let Ok(mut half) = writer.lock() else { break; };
+        if outbox.overflowed.load(Ordering::Relaxed) {
+            half.send_close(None, Some(CLOSE_POLICY_VIOLATION), "client is not keeping up with its messages");
+            half.broken = true;
+        }
+        for message in &messages {
+            if !half.send(None, message) { break; };
+        }
+        if half.broken {
+            // Wakes up the handler, which is probably waiting for input
+            if let Some(socket) = &half.socket {
+                socket.shutdown();
+            }
+            break;
+        }
+        if half.close_sent || outbox.closed.load(Ordering::Relaxed) { break; };
+    }
+    outbox.closed.store(true, Ordering::Relaxed);
+}
+
+// WebSocketParser is not thread safe; use sender() to write to the connection from other threads
 pub struct WebSocketParser<'a> {
     pub path: String,
     pub origpath: String,
+    pub query: String,
     pub is_string: bool,
     pub is_continuation: bool,
+    // The subprotocol agreed on in the handshake (see check_handshake), if any
+    pub protocol: Option<String>,
    // request: Request<'a>,
     stream: &'a mut Socket,
     connection_closed: bool,
@@ -22,7 +283,27 @@ pub struct WebSocketParser<'a> {
     length: usize,
     consumed: usize,
     opcode: u8,
-    handshake_done: bool
+    handshake_done: bool,
+    // Opcode and data of a fragmented message that is still waiting for its FIN frame
+    fragment_opcode: Option<u8>,
+    fragments: Vec<u8>,
+    // Whether the message being read (by either API) is compressed
+    inflating: bool,
+    // Decompressed payload of the current frame for data_available/read_bytes
+    inflated: Vec<u8>,
+    allow_deflate: bool,
+    deflate_no_context_takeover: bool,
+    inflater: Option<Inflater>,
+    writer: Arc<Mutex<WriteHalf>>,
+    ping_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_received: Instant,
+    last_ping: Instant,
+    // Set by the first recv_message; the keepalive read timeout is only used with that API
+    message_api: bool,
+    read_timeout: Option<Duration>,
+    outbox: Option<Arc<Outbox>>,
+    writer_thread: Option<JoinHandle<()>>
 }
 
 fn key_to_accept(data: &str) -> String {
@@ -39,6 +320,84 @@ fn key_to_accept(data: &str) -> String {
     general_purpose::STANDARD.encode(hash.as_slice())
 }
 
+// Validates an upgrade request before we switch protocols (RFC 6455 section 4.2.1) and picks the
+// subprotocol. `protocols` are the ones the handler speaks; the client's order of preference wins.
+// `origins` limits which pages may connect ("*" or an empty list allows any); requests without an
+// Origin header come from non-browser clients and are let through. On rejection returns the HTTP
+// status to answer with: 400 for a malformed request, 403 for a foreign origin, 426 for an
+// unsupported protocol version.
+pub fn check_handshake(req: &Request, protocols: &[&str], origins: &[&str]) -> Result<Option<String>, (i32, &'static str)> {
+    let connection_upgrade = req.get_headers("connection").iter()
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if req.method != "GET" || !connection_upgrade {
+        return Err((400, "not a WebSocket upgrade request"));
+    }
+    let key = general_purpose::STANDARD.decode(req.get_header("sec-websocket-key").trim());
+    if !key.map(|key| key.len() == 16).unwrap_or(false) {
+        return Err((400, "missing or invalid Sec-WebSocket-Key"));
+    }
+    if req.get_header("sec-websocket-version").trim() != "13" {
+        return Err((426, "unsupported WebSocket version"));
+    }
+    let origin = req.get_header("origin");
+    if !origin.is_empty() && !origins.is_empty() && !origins.iter().any(|allowed| *allowed == "*" || allowed.eq_ignore_ascii_case(origin.trim())) {
+        return Err((403, "origin not allowed"));
+    }
+    let offered = req.get_headers("sec-websocket-protocol").iter()
+        .flat_map(|value| value.split(',').map(|protocol| protocol.trim().to_string()).collect::<Vec<_>>())
+        .filter(|protocol| !protocol.is_empty())
+        .collect::<Vec<_>>();
+    if offered.is_empty() || protocols.is_empty() {
+        return Ok(None);
+    }
+    match offered.into_iter().find(|protocol| protocols.contains(&protocol.as_str())) {
+        Some(protocol) => Ok(Some(protocol)),
+        None => Err((400, "no supported subprotocol"))
+    }
+}
+
+fn frame_header(len: usize, opcode: i32, fin: bool) -> Vec<u8> {
+    let mut frame = vec![0u8; 10];
+    
+    frame[0] = (if fin { 128 } else { 0 } + opcode) as u8;
+    let index_start_raw_data : usize;
+    
+    if len <= 125 {
+        frame[1] = len as u8;
+        index_start_raw_data = 2;
+    } else if len <= 65535 {
+        frame[1] = 126;
+        frame[2] = ((len >> 8) & 0xFF) as u8;
+        frame[3] = (len & 0xFF) as u8;
+        index_start_raw_data = 4;
+    } else {
+        frame[1] = 127;
+        frame[2] = ((len >> 56) & 0xFF) as u8;
+        frame[3] = ((len >> 48) & 0xFF) as u8;
+        frame[4] = ((len >> 40) & 0xFF) as u8;
+        frame[5] = ((len >> 32) & 0xFF) as u8;
+        frame[6] = ((len >> 24) & 0xFF) as u8;
+        frame[7] = ((len >> 16) & 0xFF) as u8;
+        frame[8] = ((len >> 8) & 0xFF) as u8;
+        frame[9] = (len & 0xFF) as u8;
+        index_start_raw_data = 10;
+    }
+
+    frame.truncate(index_start_raw_data);
+    
+    frame
+}
+
+// All values of a header in the raw request head, which may repeat it
+fn header_values(data: &str, name: &str) -> Vec<String> {
+    data.lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .collect()
+}
+
 #[allow(dead_code)]
 #[allow(unused_assignments)]
 impl WebSocketParser<'_> {
@@ -46,6 +405,7 @@ impl WebSocketParser<'_> {
         WebSocketParser {
             path: req.path.clone(),
             origpath: req.origpath.clone(),
+            query: req.query.clone(),
             //request: req,
             stream: req.stream,
             connection_closed: false,
@@ -55,7 +415,29 @@ impl WebSocketParser<'_> {
             mask: vec![0; 4],
             opcode: 1,
             is_string: false,
-            is_continuation: false
+            is_continuation: false,
+            protocol: None,
+            fragment_opcode: None,
+            fragments: Vec::new(),
+            inflating: false,
+            inflated: Vec::new(),
+            allow_deflate: false,
+            deflate_no_context_takeover: false,
+            inflater: None,
+            writer: Arc::new(Mutex::new(WriteHalf {
+                socket: None,
+                deflater: None,
+                close_sent: false,
+                broken: false
+            })),
+            ping_interval: None,
+            idle_timeout: None,
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+            message_api: false,
+            read_timeout: None,
+            outbox: None,
+            writer_thread: None
         }
     }
     pub fn data_left(&self) -> usize {
@@ -72,12 +454,27 @@ impl WebSocketParser<'_> {
             return;
         }
         
+        let mut extensions = String::new();
+        if let Some(protocol) = &self.protocol {
+            extensions = format!("Sec-WebSocket-Protocol: {}\r\n", protocol);
+        }
+        if self.allow_deflate {
+            let offers = header_values(&header, "Sec-WebSocket-Extensions");
+            if let Some(params) = DeflateParams::negotiate(&offers, self.deflate_no_context_takeover) {
+                extensions += &format!("Sec-WebSocket-Extensions: {}\r\n", params.response());
+                self.inflater = Some(Inflater::new(params));
+                if let Ok(mut half) = self.writer.lock() {
+                    half.deflater = Some(Deflater::new(params));
+                }
+            }
+        }
         let response = format!(
             "HTTP/1.1 101 Switching Protocols\r\n\
             Connection: Upgrade\r\n\
             Upgrade: websocket\r\n\
-            Sec-WebSocket-Accept: {}\r\n\r\n",
-            key
+            Sec-WebSocket-Accept: {}\r\n\
+            {}\r\n",
+            key, extensions
         );
 
         self.write_to_stream(response.as_bytes());
@@ -86,12 +483,52 @@ impl WebSocketParser<'_> {
     pub fn connected(&self) -> bool {
         return !self.connection_closed;
     }
-    fn read(&mut self, bytes: usize) -> Result<Vec<u8>, bool> {
+    // Offer permessage-deflate (RFC 7692) to clients that ask for it. Call before do_handshake.
+    // `no_context_takeover` resets our compressor after every message to save memory.
+    pub fn allow_compression(&mut self, no_context_takeover: bool) {
+        self.allow_deflate = true;
+        self.deflate_no_context_takeover = no_context_takeover;
+    }
+    // Whether permessage-deflate was negotiated in the handshake
+    pub fn compression_enabled(&self) -> bool {
+        self.inflater.is_some()
+    }
+    // Checks the RSV bits of a frame's first byte; only RSV1 on the first frame of a data message
+    // is allowed, and only once compression has been negotiated
+    fn check_reserved_bits(&mut self, head: u8) -> Result<bool, Error> {
+        let compressed = head & RSV1 != 0;
+        let opcode = head & 0b00001111;
+        if head & 0b00110000 != 0 || (compressed && (self.inflater.is_none() || opcode == OP_CONTINUATION || opcode >= OP_CLOSE)) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "reserved bits set without a negotiated extension"));
+        }
+        Ok(compressed)
+    }
+    // Inflates a frame of a compressed message into `out`, failing the connection on bad data
+    fn inflate(&mut self, payload: &[u8], fin: bool, out: &mut Vec<u8>) -> Result<(), Error> {
+        let limit = MAX_MESSAGE_SIZE;
+        let result = match self.inflater.as_mut() {
+            Some(inflater) => inflater.decompress(payload, fin, out, limit),
+            None => Err(String::from("compression was not negotiated"))
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) if out.len() > limit => Err(self.fail(CLOSE_TOO_BIG, &err)),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "invalid compressed data"))
+        }
+    }
+    fn read(&mut self, bytes: usize) -> Result<Vec<u8>, Error> {
         let mut bytes = bytes;
         if self.consumed + bytes > self.length || bytes == 0 {
             //Consume the whole/rest of the body
             bytes = self.length - self.consumed;
         }
+        if !self.inflated.is_empty() {
+            return Ok(self.inflated.drain(..bytes).collect());
+        }
+        self.read_raw(bytes)
+    }
+    // Reads exactly `bytes` bytes, ignoring the frame-level length bookkeeping
+    fn read_raw(&mut self, bytes: usize) -> Result<Vec<u8>, Error> {
         if bytes == 0 {
             return Ok(b"".to_vec());
         }
@@ -101,191 +538,161 @@ impl WebSocketParser<'_> {
             let mut reading = vec![0; bytes-read];
             match self.stream.read(&mut reading) {
                 Ok(bytes_read) => {
+                    if bytes_read == 0 {
+                        self.connection_closed = true;
+                        return Err(Error::ConnectionClosed);
+                    }
                     read += bytes_read;
-                    //println!("{} bytes read", bytes_read);
+                    self.last_received = Instant::now();
                     reading.truncate(bytes_read);
                     buffer.append(&mut reading);
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(10));
+                Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    self.keepalive()?;
+                    // A read timeout has done the waiting already; only a non-blocking socket needs this
+                    if self.read_timeout.is_none() {
+                        thread::sleep(Duration::from_millis(10));
+                    }
                 }
-                Err(_) => {
+                Err(e) => {
                     self.connection_closed = true;
-                    return Err(true);
+                    return Err(Error::Io(e));
                 }
             }
         }
         Ok(buffer)
     }
     fn write_to_stream(&mut self, data:&[u8]) -> bool {
+        self.with_writer(|half, stream| half.write(stream, data))
+    }
+    // Runs `write` on the shared write side, handing it this parser's stream
+    fn with_writer(&mut self, write: impl FnOnce(&mut WriteHalf, Option<&mut Socket>) -> bool) -> bool {
         if self.connection_closed { return false; };
-        let mut rv = true;
-        loop {
-            match self.stream.write(data) {
-                Ok(_e) => {
-                    rv = true;
-                    break;
-                },
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        thread::sleep(Duration::from_millis(10));
-                    } else {
-                        rv = false;
-                        self.connection_closed = true;
-                        break;
-                    }
-                },
-            };
+        let Ok(mut half) = self.writer.lock() else { return false; };
+        let written = write(&mut half, Some(&mut *self.stream));
+        if half.broken {
+            self.connection_closed = true;
         }
-        rv
+        written
     }
-    fn get_header(&self, len: usize, opcode: i32) -> Vec<u8> {
-        let mut frame = vec![0u8; 10];
-        
-        frame[0] = (128 + opcode) as u8;
-        let index_start_raw_data : usize;
-        
-        if len <= 125 {
-            frame[1] = len as u8;
-            index_start_raw_data = 2;
-        } else if len <= 65535 {
-            frame[1] = 126;
-            frame[2] = ((len >> 8) & 0xFF) as u8;
-            frame[3] = (len & 0xFF) as u8;
-            index_start_raw_data = 4;
-        } else {
-            frame[1] = 127;
-            frame[2] = ((len >> 56) & 0xFF) as u8;
-            frame[3] = ((len >> 48) & 0xFF) as u8;
-            frame[4] = ((len >> 40) & 0xFF) as u8;
-            frame[5] = ((len >> 32) & 0xFF) as u8;
-            frame[6] = ((len >> 24) & 0xFF) as u8;
-            frame[7] = ((len >> 16) & 0xFF) as u8;
-            frame[8] = ((len >> 8) & 0xFF) as u8;
-            frame[9] = (len & 0xFF) as u8;
-            index_start_raw_data = 10;
-        }
-
-        frame.truncate(index_start_raw_data);
-        
-        frame
+    fn close_sent(&self) -> bool {
+        self.writer.lock().map(|half| half.close_sent).unwrap_or(true)
     }
     pub fn write_data(&mut self, write_header: bool, size: usize, data:&[u8], opcode: i32) {
-        if write_header {
-            let header = self.get_header(size, opcode);
-            self.write_to_stream(&header);
-        }
-        if data.len() > 0 {
-            self.write_to_stream(data);
+        // One write, so a sender's messages can't end up between the header and the data
+        let mut frame = if write_header { frame_header(size, opcode, true) } else { Vec::new() };
+        frame.extend_from_slice(data);
+        if !frame.is_empty() {
+            self.write_to_stream(&frame);
         }
     }
     pub fn data_available(&mut self) -> bool {
         if self.connection_closed { return false; }
         let mut buf = [0; 5];
-        let len = self.stream.peek(&mut buf).unwrap_or(0);
-        if len == 0 {
-            self.connection_closed = true;
-            return false;
+        match self.stream.peek(&mut buf) {
+            Ok(0) => {
+                self.connection_closed = true;
+                return false;
+            }
+            Ok(_) => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+                // Nothing yet, on a non-blocking socket or after close() set a read timeout
+                let _ = self.keepalive();
+                return false;
+            }
+            Err(_) => {
+                self.connection_closed = true;
+                return false;
+            }
         }
         if self.length - self.consumed > 0 { return true; }
         
         self.consumed = 0;
-        self.length = 2;
-        match self.read(2) {
-            Ok(head) => {
-                self.length = 0;
-                self.consumed = 0;
-                let opcode:u8;
-                if head[0] >= 128 {
-                    opcode = head[0]-128;
-                } else {
-                    opcode = head[0];
-                }
-                self.is_string = opcode == 1;
-                self.is_continuation = opcode == 0;
-                //println!("opcode: {}", opcode);
-                
+        self.length = 0;
+        let Ok(head) = self.read_raw(2) else { return false; };
+        let Ok(compressed) = self.check_reserved_bits(head[0]) else { return false; };
+        let opcode = head[0] & 0b00001111;
+        
 //              0 - More data
 //              1 - Text
 //              2 - Binary
 //              8 - Connection Closed
 //              9 - Ping
 //              10 - Pong
-                
-                if !self.is_continuation {
-                    self.opcode = opcode;
-                }
-                
-                let mask = (head[1] & 0b10000000) != 0;
-                let msglen = head[1] as usize & 0b01111111;
-                
-                //println!("msglen: {}", msglen);
-                let mut length:usize = 0;
-                
-                match msglen {
-                    126 => {
-                        self.length = 2;
-                        match self.read(2) {
-                            Ok(size) => {
-                                let size_value = u16::from_be_bytes([size[0], size[1]]);
-                                length = size_value as usize;
-                            },
-                            Err(_) => {
-                                return false;
-                            }
-                        }
-                    },
-                    127 => {
-                        self.length = 8;
-                        match self.read(8) {
-                            Ok(size) => {
-                                let mut size_array = [0; 8];
-                                size_array.copy_from_slice(&size[..8]);
-                                length = u64::from_be_bytes(size_array) as usize;
-                            },
-                            Err(_) => {
-                                return false;
-                            }
-                        }
-                    },
-                    _ => {
-                        length = msglen;
-                    }
-                }
-                
-                if opcode == 8 {
-                    self.connection_closed = true;
-                    return false;
-                }
-                //println!("{}, {}", msglen, length);
-                
-                if mask {
-                    self.length = 4;
-                    match self.read(4) {
-                        Ok(masks) => {
-                            self.length = length;
-                            self.consumed = 0;
-                            self.mask[0] = masks[0];
-                            self.mask[1] = masks[1];
-                            self.mask[2] = masks[2];
-                            self.mask[3] = masks[3];
-                            if opcode == 9 {
-                                self.write_data(true, 0, &[0; 0], 10);
-                            }
-                            if msglen == 0 || opcode == 9 { return self.data_available(); }
-                        },
-                        Err(_) => {
-                            self.stream.shutdown();
-                            self.connection_closed = true;
-                        }
-                    }
-                }
-                
-                return true;
+        
+        let mask = (head[1] & 0b10000000) != 0;
+        let msglen = head[1] as usize & 0b01111111;
+        
+        //println!("msglen: {}", msglen);
+        let length = match msglen {
+            126 => {
+                let Ok(size) = self.read_raw(2) else { return false; };
+                u16::from_be_bytes([size[0], size[1]]) as usize
             },
-            Err(_) => {}
+            127 => {
+                let Ok(size) = self.read_raw(8) else { return false; };
+                let mut size_array = [0; 8];
+                size_array.copy_from_slice(&size[..8]);
+                u64::from_be_bytes(size_array) as usize
+            },
+            _ => msglen
+        };
+        //println!("{}, {}", msglen, length);
+        
+        if !mask {
+            self.fail(CLOSE_PROTOCOL_ERROR, "client frames must be masked");
+            return false;
+        }
+        let Ok(masks) = self.read_raw(4) else { return false; };
+        self.mask.copy_from_slice(&masks[..4]);
+        
+        if opcode >= OP_CLOSE {
+            if length > 125 || head[0] & 0b10000000 == 0 {
+                self.fail(CLOSE_PROTOCOL_ERROR, "control frames must not be fragmented or longer than 125 bytes");
+                return false;
+            }
+            let Ok(mut payload) = self.read_raw(length) else { return false; };
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= self.mask[i % 4];
+            }
+            return match self.handle_control(opcode, &payload) {
+                Ok(Message::Close(..)) | Err(_) => false,
+                Ok(_) => self.data_available()
+            };
+        }
+        
+        if opcode > OP_BINARY {
+            self.fail(CLOSE_PROTOCOL_ERROR, &format!("unknown opcode {}", opcode));
+            return false;
+        }
+        self.is_string = opcode == OP_TEXT;
+        self.is_continuation = opcode == OP_CONTINUATION;
+        if !self.is_continuation {
+            self.opcode = opcode;
+            self.inflating = compressed;
+        }
+        self.length = length;
+        self.consumed = 0;
+        if self.inflating {
+            // Compressed frames are inflated whole and then handed out by read_bytes
+            if length > MAX_MESSAGE_SIZE {
+                self.fail(CLOSE_TOO_BIG, "message too big");
+                return false;
+            }
+            let fin = head[0] & 0b10000000 != 0;
+            let Ok(mut payload) = self.read_raw(length) else { return false; };
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= self.mask[i % 4];
+            }
+            let mut inflated = Vec::new();
+            if self.inflate(&payload, fin, &mut inflated).is_err() { return false; };
+            self.inflating = !fin;
+            self.mask = vec![0; 4];
+            self.length = inflated.len();
+            self.inflated = inflated;
         }
-        false
+        if self.length == 0 { return self.data_available(); }
+        true
     }
     pub fn read_bytes(&mut self, bytes: usize) -> Vec<u8> {
         match self.read(bytes) {
@@ -311,10 +718,275 @@ impl WebSocketParser<'_> {
         return String::from_utf8_lossy(&self.read_bytes(self.length - self.consumed)).to_string();
     }
     pub fn write_string(&mut self, out: &str) {
-        let data = out.as_bytes();
-        self.write_data(true, data.len(), data, 1);
+        self.write_message(OP_TEXT, out.as_bytes());
     }
     pub fn write_binary(&mut self, data: &[u8]) {
-        self.write_data(true, data.len(), data, 2);
+        self.write_message(OP_BINARY, data);
+    }
+    fn write_message(&mut self, opcode: u8, data: &[u8]) -> bool {
+        self.with_writer(|half, stream| half.send_data(stream, opcode, data))
+    }
+    fn read_frame(&mut self) -> Result<Frame, Error> {
+        let head = self.read_raw(2)?;
+        let fin = head[0] & 0b10000000 != 0;
+        let compressed = self.check_reserved_bits(head[0])?;
+        let opcode = head[0] & 0b00001111;
+        let masked = head[1] & 0b10000000 != 0;
+        let length = match head[1] & 0b01111111 {
+            126 => {
+                let size = self.read_raw(2)?;
+                u16::from_be_bytes([size[0], size[1]]) as u64
+            }
+            127 => {
+                let size = self.read_raw(8)?;
+                let mut size_array = [0; 8];
+                size_array.copy_from_slice(&size[..8]);
+                u64::from_be_bytes(size_array)
+            }
+            len => len as u64
+        };
+        if !masked {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+        }
+        if opcode >= OP_CLOSE && (!fin || length > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "control frames must not be fragmented or longer than 125 bytes"));
+        }
+        if length > (MAX_MESSAGE_SIZE - self.fragments.len()) as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "message too big"));
+        }
+        let mask = self.read_raw(4)?;
+        let mut payload = self.read_raw(length as usize)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame {
+            fin,
+            compressed,
+            opcode,
+            payload
+        })
+    }
+    // Answers pings and completes the close handshake. Returns the control message for the caller.
+    fn handle_control(&mut self, opcode: u8, payload: &[u8]) -> Result<Message, Error> {
+        match opcode {
+            OP_PING => {
+                self.write_frame(true, OP_PONG, payload);
+                Ok(Message::Ping(payload.to_vec()))
+            }
+            OP_PONG => Ok(Message::Pong(payload.to_vec())),
+            OP_CLOSE => {
+                let (code, reason) = match payload.len() {
+                    0 => (None, String::new()),
+                    1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "close frame with a truncated status code")),
+                    _ => {
+                        let code = u16::from_be_bytes([payload[0], payload[1]]);
+                        if !is_valid_close_code(code) {
+                            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid close status code"));
+                        }
+                        let Ok(reason) = String::from_utf8(payload[2..].to_vec()) else {
+                            return Err(self.fail(CLOSE_INVALID_DATA, "close reason is not valid UTF-8"));
+                        };
+                        (Some(code), reason)
+                    }
+                };
+                // Echo the status code unless this is the answer to our own close frame
+                if !self.close_sent() {
+                    self.send_close(code, "");
+                }
+                self.connection_closed = true;
+                self.stream.shutdown();
+                Ok(Message::Close(code, reason))
+            }
+            opcode => Err(self.fail(CLOSE_PROTOCOL_ERROR, &format!("unknown opcode {}", opcode)))
+        }
+    }
+    // Blocks until a complete message arrives. Fragments are reassembled by their FIN bit; control
+    // frames that arrive between fragments are returned on their own and the reassembly picks up on
+    // the next call. Pings are answered and a close frame is echoed automatically, but both are
+    // still returned. Protocol violations close the connection with the matching status code.
+    // Don't mix this with the frame-level data_available/read_bytes API on the same connection.
+    pub fn recv_message(&mut self) -> Result<Message, Error> {
+        if self.connection_closed { return Err(Error::ConnectionClosed); };
+        if !self.message_api {
+            self.message_api = true;
+            self.update_read_timeout();
+        }
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                OP_CONTINUATION => {
+                    if self.fragment_opcode.is_none() {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "continuation frame without a message to continue"));
+                    }
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.fragment_opcode.is_some() {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "new message started before the previous one was finished"));
+                    }
+                    self.fragment_opcode = Some(frame.opcode);
+                    self.inflating = frame.compressed;
+                }
+                opcode => return self.handle_control(opcode, &frame.payload)
+            }
+            if self.inflating {
+                let mut fragments = std::mem::take(&mut self.fragments);
+                self.inflate(&frame.payload, frame.fin, &mut fragments)?;
+                self.fragments = fragments;
+            } else {
+                self.fragments.extend_from_slice(&frame.payload);
+            }
+            if !frame.fin { continue; };
+            self.inflating = false;
+            let data = std::mem::take(&mut self.fragments);
+            return match self.fragment_opcode.take() {
+                Some(OP_TEXT) => match String::from_utf8(data) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "text message is not valid UTF-8"))
+                },
+                _ => Ok(Message::Binary(data))
+            };
+        }
+    }
+    fn send_close(&mut self, code: Option<u16>, reason: &str) -> bool {
+        self.with_writer(|half, stream| half.send_close(stream, code, reason))
+    }
+    // Closes the connection after a protocol violation, telling the peer why
+    fn fail(&mut self, code: u16, reason: &str) -> Error {
+        if !self.connection_closed && !self.close_sent() {
+            self.send_close(Some(code), reason);
+        }
+        self.connection_closed = true;
+        self.stream.shutdown();
+        Error::Protocol(reason.to_string())
+    }
+    // Starts the close handshake and waits (up to CLOSE_TIMEOUT) for the peer to answer it.
+    // Anything the peer still sends in the meantime is discarded.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.connection_closed { return; };
+        self.send_close(Some(code), reason);
+        self.idle_timeout = Some(CLOSE_TIMEOUT);
+        self.last_received = Instant::now();
+        self.set_read_timeout(Some(KEEPALIVE_POLL));
+        while !self.connection_closed {
+            match self.read_frame() {
+                Ok(frame) if frame.opcode == OP_CLOSE => {
+                    self.connection_closed = true;
+                }
+                Ok(_) => {}
+                Err(_) => break
+            }
+        }
+        self.connection_closed = true;
+        self.stream.shutdown();
+    }
+    // Sends a ping after `ping_interval` without traffic from the peer and closes the connection with
+    // 1001 after `idle_timeout` without traffic (pongs count). Zero disables either. Only connections
+    // read through recv_message are kept alive; data_available/read_bytes keep blocking reads.
+    pub fn set_keepalive(&mut self, ping_interval: Duration, idle_timeout: Duration) {
+        self.ping_interval = Some(ping_interval).filter(|interval| !interval.is_zero());
+        self.idle_timeout = Some(idle_timeout).filter(|timeout| !timeout.is_zero());
+        self.update_read_timeout();
+    }
+    fn update_read_timeout(&mut self) {
+        let keepalive = self.message_api && (self.ping_interval.is_some() || self.idle_timeout.is_some());
+        self.set_read_timeout(if keepalive { Some(KEEPALIVE_POLL) } else { None });
+    }
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        let _ = self.stream.set_read_timeout(timeout);
+    }
+    // A handle other threads can use to send to this connection. The first call starts a writer
+    // thread for the queued messages, so they go out whatever the handler is doing meanwhile.
+    pub fn sender(&mut self) -> WebSocketSender {
+        if let Some(outbox) = &self.outbox {
+            return WebSocketSender {
+                outbox: outbox.clone()
+            };
+        }
+        let outbox = Arc::new(Outbox {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            closed: AtomicBool::new(true),
+            overflowed: AtomicBool::new(false)
+        });
+        self.outbox = Some(outbox.clone());
+        if !self.connection_closed {
+            if let (Ok(socket), Ok(mut half)) = (self.stream.writer(), self.writer.lock()) {
+                half.socket = Some(socket);
+                outbox.closed.store(false, Ordering::Relaxed);
+                let (outbox, writer) = (outbox.clone(), self.writer.clone());
+                self.writer_thread = Some(thread::spawn(move || write_queued(outbox, writer)));
+            }
+        }
+        WebSocketSender {
+            outbox
+        }
+    }
+    // Called whenever a read times out
+    fn keepalive(&mut self) -> Result<(), Error> {
+        if let Some(timeout) = self.idle_timeout {
+            if self.last_received.elapsed() >= timeout {
+                if !self.close_sent() {
+                    self.send_close(Some(CLOSE_GOING_AWAY), "idle timeout");
+                }
+                self.connection_closed = true;
+                self.stream.shutdown();
+                return Err(Error::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "WebSocket idle timeout")));
+            }
+        }
+        if let Some(interval) = self.ping_interval {
+            if !self.close_sent() && self.last_received.elapsed() >= interval && self.last_ping.elapsed() >= interval {
+                self.write_frame(true, OP_PING, &[]);
+                self.last_ping = Instant::now();
+            }
+        }
+        Ok(())
+    }
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> bool {
+        self.with_writer(|half, stream| half.write_frame(stream, fin, opcode, payload))
+    }
+    // Returns false if the connection is closed
+    // A close message starts the close handshake without waiting for the answer; use close() to wait
+    pub fn send_message(&mut self, message: &Message) -> bool {
+        self.with_writer(|half, stream| half.send(stream, message))
+    }
+    // Sends a text or binary message as frames of at most `fragment_size` bytes (control messages
+    // can't be fragmented and are sent whole). Text is split on byte boundaries, as RFC 6455 allows.
+    pub fn send_fragmented(&mut self, message: &Message, fragment_size: usize) -> bool {
+        self.with_writer(|half, stream| half.send_fragmented(stream, message, fragment_size))
+    }
+}
+
+// Handlers that just return still close the connection cleanly
+impl Drop for WebSocketParser<'_> {
+    fn drop(&mut self) {
+        if let Some(outbox) = &self.outbox {
+            // Set with the queue locked so the writer thread can't miss it
+            if let Ok(_queue) = outbox.queue.lock() {
+                outbox.closed.store(true, Ordering::Relaxed);
+            }
+            outbox.ready.notify_all();
+        }
+        // Lets whatever was queued before this go out ahead of the close frame, unless the peer has
+        // stopped reading; then the writer thread is stuck, and closing the socket gets it unstuck
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let started = Instant::now();
+            while !writer_thread.is_finished() && started.elapsed() < DRAIN_TIMEOUT {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if !writer_thread.is_finished() {
+                self.connection_closed = true;
+                self.stream.shutdown();
+            }
+            let _ = writer_thread.join();
+        }
+        if self.handshake_done && !self.connection_closed {
+            if !self.close_sent() {
+                self.send_close(Some(CLOSE_NORMAL), "");
+            }
+            self.connection_closed = true;
+            self.stream.shutdown();
+        }
     }
 }
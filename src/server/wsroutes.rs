@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use crate::{
+    Request,
+    Settings,
+    relative_path,
+    wsparser::WebSocketParser
+};
+
+// Runs for the whole lifetime of a WebSocket connection; the connection is closed when it returns.
+// Closures can carry their own state, shared between connections (and so threads) of the route.
+pub type WebSocketHandler = Arc<dyn Fn(WebSocketParser, Settings) + Send + Sync>;
+
+// Runs before every upgrade, e.g. to authenticate the client. Returning the request lets the upgrade
+// go ahead; otherwise the check has answered the request itself (401, 403, 429, ...).
+pub type UpgradeCheck = for<'a, 'b> fn(Request<'a>, Settings<'b>) -> Option<Request<'a>>;
+
+pub struct WebSocketRoute {
+    pub path: String,
+    // Subprotocols this endpoint speaks; empty falls back to Settings::websocket_protocols
+    pub protocols: Vec<String>,
+    pub handler: WebSocketHandler
+}
+
+// Maps request paths to WebSocket handlers. Upgrade requests for any other path get a 404.
+#[derive(Clone, Default)]
+pub struct WebSocketRoutes {
+    routes: Vec<Arc<WebSocketRoute>>,
+    check: Option<UpgradeCheck>
+}
+
+impl WebSocketRoutes {
+    pub fn new() -> WebSocketRoutes {
+        WebSocketRoutes {
+            routes: Vec::new(),
+            check: None
+        }
+    }
+    // Registering a path again replaces its handler
+    pub fn add(&mut self, path: &str, handler: impl Fn(WebSocketParser, Settings) + Send + Sync + 'static) -> &mut WebSocketRoutes {
+        self.add_with_protocols(path, &[], handler)
+    }
+    pub fn add_with_protocols(&mut self, path: &str, protocols: &[&str], handler: impl Fn(WebSocketParser, Settings) + Send + Sync + 'static) -> &mut WebSocketRoutes {
+        let path = relative_path("", path);
+        self.routes.retain(|route| route.path != path);
+        self.routes.push(Arc::new(WebSocketRoute {
+            path,
+            protocols: protocols.iter().map(|protocol| protocol.to_string()).collect(),
+            handler: Arc::new(handler)
+        }));
+        self
+    }
+    // Applies to every route, including ones added later
+    pub fn set_upgrade_check(&mut self, check: UpgradeCheck) -> &mut WebSocketRoutes {
+        self.check = Some(check);
+        self
+    }
+    pub fn upgrade_check(&self) -> Option<UpgradeCheck> {
+        self.check
+    }
+    pub fn remove(&mut self, path: &str) -> bool {
+        let path = relative_path("", path);
+        let count = self.routes.len();
+        self.routes.retain(|route| route.path != path);
+        self.routes.len() != count
+    }
+    // `path` is the decoded request path, as in Request::path
+    pub fn find(&self, path: &str) -> Option<Arc<WebSocketRoute>> {
+        self.routes.iter().find(|route| route.path == path).cloned()
+    }
+    pub fn paths(&self) -> Vec<&str> {
+        self.routes.iter().map(|route| route.path.as_str()).collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
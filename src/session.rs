@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant}
+};
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    rand::rand_bytes,
+    sign::Signer,
+    memcmp
+};
+
+pub static SESSION_COOKIE: &str = "sws_session";
+
+struct Session {
+    username: String,
+    expires: Instant
+}
+
+static SESSIONS: OnceLock<Mutex<HashMap<String, Session>>> = OnceLock::new();
+static GENERATED_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+fn sessions() -> &'static Mutex<HashMap<String, Session>> {
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn random_hex(len: usize) -> String {
+    let mut buf = vec![0; len];
+    if rand_bytes(&mut buf).is_err() {
+        // Never hand out a predictable id
+        panic!("Failed to generate random bytes");
+    }
+    to_hex(&buf)
+}
+
+// An empty configured secret means sessions only live as long as the process
+fn secret(configured: &str) -> Vec<u8> {
+    if !configured.is_empty() {
+        return configured.as_bytes().to_vec();
+    }
+    GENERATED_SECRET.get_or_init(|| random_hex(32).into_bytes()).clone()
+}
+
+pub fn sign(id: &str, secret_key: &str) -> String {
+    let Ok(key) = PKey::hmac(&secret(secret_key)) else { return String::new(); };
+    let Ok(mut signer) = Signer::new(MessageDigest::sha256(), &key) else { return String::new(); };
+    if signer.update(id.as_bytes()).is_err() { return String::new(); };
+    signer.sign_to_vec().map(|sig| to_hex(&sig)).unwrap_or_default()
+}
+
+fn verify(cookie: &str, secret_key: &str) -> Option<String> {
+    let (id, signature) = cookie.split_once('.')?;
+    let expected = sign(id, secret_key);
+    if expected.is_empty() || expected.len() != signature.len() || !memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+        return None;
+    }
+    Some(id.to_string())
+}
+
+// Returns the cookie value for a fresh session
+pub fn create(username: &str, timeout: u64, secret_key: &str) -> String {
+    let id = random_hex(24);
+    let Ok(mut sessions) = sessions().lock() else { return String::new(); };
+    let now = Instant::now();
+    sessions.retain(|_, session| session.expires > now);
+    sessions.insert(id.clone(), Session {
+        username: username.to_string(),
+        expires: now + Duration::from_secs(timeout)
+    });
+    format!("{}.{}", id, sign(&id, secret_key))
+}
+
+// Returns the username the session belongs to, if the cookie is authentic and has not expired
+pub fn validate(cookie: &str, secret_key: &str) -> Option<String> {
+    let id = verify(cookie, secret_key)?;
+    let Ok(mut sessions) = sessions().lock() else { return None; };
+    let session = sessions.get(&id)?;
+    if session.expires <= Instant::now() {
+        sessions.remove(&id);
+        return None;
+    }
+    Some(session.username.clone())
+}
+
+pub fn destroy(cookie: &str, secret_key: &str) {
+    let Some(id) = verify(cookie, secret_key) else { return; };
+    if let Ok(mut sessions) = sessions().lock() {
+        sessions.remove(&id);
+    }
+}
@@ -1,12 +1,50 @@
 use server::{
     Server,
     Settings,
+    ListenAddr,
+    error::Error,
+    logging,
     file_system::GetByPath,
     Request,
-    wsparser::WebSocketParser,
+    wsparser::{WebSocketParser, Message},
+    wshub,
     httpcodes::get_http_message,
-    decode_base64
+    cookies::{SetCookie, SameSite},
+    decode_base64,
+    parse_query,
+    url_encode,
+    serve_metrics
 };
+use crate::{
+    acl::{self, Action},
+    session::{self, SESSION_COOKIE},
+    users::{self, Permissions},
+    tokens,
+    digest::{self, DigestResult},
+    rate_limit,
+    status
+};
+
+static LOGIN_PAGE: &str = include_str!("login-page-template.html");
+static LOGIN_PATH: &str = "/__login";
+static LOGOUT_PATH: &str = "/__logout";
+static HEALTH_PATH: &str = "/healthz";
+static STATUS_PATH: &str = "/status";
+const MAX_LOGIN_BODY: usize = 64 * 1024;
+
+pub fn escape_html(input: &str) -> String {
+    input.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+// Only allow redirecting back to a path on this server. Browsers drop tabs and line breaks from
+// URLs, so "/\t/evil.com" would become "//evil.com"; any control character is refused.
+fn safe_redirect(target: &str) -> &str {
+    if target.starts_with('/') && !target.starts_with("//") && !target.contains('\\') && !target.chars().any(|c| c.is_control()) {
+        target
+    } else {
+        "/"
+    }
+}
 
 
 pub struct SimpleWebServer {
@@ -15,65 +53,229 @@ pub struct SimpleWebServer {
 
 #[allow(dead_code)]
 impl SimpleWebServer {
-    fn log(msg: String) {
-        println!("{}", msg);
-    }
     pub fn new(opts: Settings<'static>) -> SimpleWebServer {
+        if !opts.http_auth_users_file.is_empty() {
+            if let Err(err) = users::load(opts.http_auth_users_file) {
+                // Nobody will be able to log in, rather than falling back to anonymous access
+                logging::error(err);
+            }
+        }
+        if opts.http_auth_digest && !opts.http_auth_users_file.is_empty() {
+            logging::warn("Digest authentication only works for the user given with --auth, not for users file entries");
+        }
+        if !opts.http_auth_tokens_file.is_empty() {
+            if let Err(err) = tokens::load(opts.http_auth_tokens_file) {
+                logging::error(err);
+            }
+        }
+        if !opts.acl_file.is_empty() {
+            if let Err(err) = acl::load(opts.acl_file) {
+                // Fail closed: refuse everything rather than serving without the rules
+                logging::error(err);
+                acl::deny_all();
+            }
+        }
+        let mut server = Server::new(opts, SimpleWebServer::on_request);
+        server.websocket_routes().set_upgrade_check(SimpleWebServer::allow_upgrade);
+        if !opts.websocket_echo.is_empty() {
+            server.websocket_routes().add(opts.websocket_echo, SimpleWebServer::echo);
+        }
+        if !opts.websocket_broadcast.is_empty() {
+            server.websocket_routes().add(opts.websocket_broadcast, SimpleWebServer::broadcast);
+        }
         SimpleWebServer {
-            server: Server::new(opts, SimpleWebServer::on_request, SimpleWebServer::on_websocket)
+            server
         }
     }
-    pub fn start(&mut self) -> bool {
-        self.server.start()
+    pub fn start(&mut self) -> Result<ListenAddr, Error> {
+        let addr = self.server.start()?;
+        status::mark_started();
+        Ok(addr)
     }
     pub fn terminate(&mut self) {
         self.server.terminate()
     }
-    fn validate_auth(auth: String, username: &str, password: &str) -> bool {
-        if auth.is_empty() { return false; };
-        if !auth.to_lowercase().starts_with("basic ") { return false; };
+    fn presented_token(res: &Request, opts: Settings) -> Option<String> {
+        if opts.http_auth_tokens_file.is_empty() { return None; };
+        let api_key = res.get_header("x-api-key");
+        if !api_key.is_empty() {
+            return Some(api_key);
+        }
+        let auth = res.get_header("authorization");
+        if auth.to_lowercase().starts_with("bearer ") {
+            return Some(auth[7..].trim().to_string());
+        }
+        None
+    }
+    // Returns the permissions of the authenticated user or API token
+    fn validate_auth(res: &Request, opts: Settings) -> Option<Permissions> {
+        if let Some(token) = Self::presented_token(res, opts) {
+            return tokens::verify(&token);
+        }
+        let auth = res.get_header("authorization");
+        if auth.is_empty() { return None; };
+        if opts.http_auth_digest {
+            // Basic is deliberately not accepted, it would send the password in the clear
+            return match digest::verify(&auth, &res.method, &res.path, opts.session_secret, |username| Self::digest_password(opts, username)) {
+                DigestResult::Valid(username) => Some(Self::permissions(opts, &username)),
+                _ => None
+            };
+        }
+        if !auth.to_lowercase().starts_with("basic ") { return None; };
         let base64_data = &auth[6..];
         let decoded_str = decode_base64(base64_data.as_bytes());
-        if decoded_str.is_empty() || decoded_str == ":" { return false; };
-        if let Some(index) = decoded_str.find(':') {
-            let auth_username = &decoded_str[0..index];
-            let auth_password = &decoded_str[index + 1..];
-            
-            return auth_username == username && auth_password == password;
-        }
-        false
+        if decoded_str.is_empty() || decoded_str == ":" { return None; };
+        let (auth_username, auth_password) = decoded_str.split_once(':')?;
+        if Self::check_credentials(opts, auth_username, auth_password) {
+            return Some(Self::permissions(opts, auth_username));
+        }
+        None
+    }
+    // Digest needs the plain text password, so only the single configured user can use it
+    fn digest_password(opts: Settings, username: &str) -> Option<String> {
+        if opts.http_auth_username.is_empty() || username != opts.http_auth_username {
+            return None;
+        }
+        Some(opts.http_auth_password.to_string())
+    }
+    fn check_credentials(opts: Settings, username: &str, password: &str) -> bool {
+        if !opts.http_auth_users_file.is_empty() {
+            return users::verify(username, password);
+        }
+        if opts.http_auth_username.is_empty() { return false; };
+        let username_matches = users::constant_time_eq(username, opts.http_auth_username);
+        let password_matches = users::constant_time_eq(password, opts.http_auth_password);
+        username_matches && password_matches
+    }
+    fn permissions(opts: Settings, username: &str) -> Permissions {
+        if opts.http_auth_users_file.is_empty() {
+            Permissions::all()
+        } else {
+            users::permissions(username)
+        }
     }
-    fn on_websocket(mut res: WebSocketParser, _opts: Settings) {
+    // Sends every text and binary message straight back, for testing clients and proxies
+    fn echo(mut res: WebSocketParser, _opts: Settings) {
         while res.connected() {
-            if res.data_available() {
-                if res.is_string {
-                    let data = res.read_string();
-                    res.write_string(&data);
-                } else {
-                    let mut data : Vec<u8> = [].to_vec();
-                    loop {
-                        if data.len() + res.data_left() > 16 * 1024 * 1024 { // 16mb I think
-                            println!("Too much data... {}", data.len() + res.data_left());
-                        }
-                        while res.data_left() > 0 {
-                            let mut new_data = res.read_bytes(1024);
-                            data.append(&mut new_data);
-                        }
-                        if res.data_available() && res.is_continuation {
-                            continue;
-                        }
-                        break;
+            match res.recv_message() {
+                Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
+                    res.send_message(&message);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    logging::debug(format!("WebSocket closed: {}", err));
+                    break;
+                }
+            }
+        }
+    }
+    // Relays every text and binary message to the other members of the channels in ?channel=...
+    // (the "default" channel when none are given)
+    fn broadcast(mut res: WebSocketParser, _opts: Settings) {
+        let mut channels = parse_query(&res.query).into_iter()
+            .filter(|(name, value)| name == "channel" && !value.is_empty())
+            .map(|(_, value)| value)
+            .collect::<Vec<_>>();
+        if channels.is_empty() {
+            channels.push(String::from("default"));
+        }
+        let hub = wshub::global();
+        let sender = res.sender();
+        for channel in channels.iter() {
+            hub.join(channel, &sender);
+        }
+        while res.connected() {
+            match res.recv_message() {
+                Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
+                    for channel in channels.iter() {
+                        hub.broadcast_except(channel, &message, &sender);
                     }
-                    
-                    res.write_data(true, data.len(), &[], 2);
-                    res.write_data(false, 0, &data, 2);
                 }
+                Ok(_) => {}
+                Err(err) => {
+                    logging::debug(format!("WebSocket closed: {}", err));
+                    break;
+                }
+            }
+        }
+        hub.leave_all(&sender);
+    }
+    // Answers with a 429 when the client is over the rate limit
+    fn check_rate_limit<'a>(res: Request<'a>, opts: Settings) -> Option<Request<'a>> {
+        if let Some(ip) = res.peer_addr().map(|addr| addr.ip()) {
+            if let Err(retry_after) = rate_limit::check_request(ip, opts.rate_limit, opts.rate_limit_burst) {
+                Self::too_many_requests(res, opts, retry_after);
+                return None;
+            }
+        }
+        Some(res)
+    }
+    // Applies the ACL and checks credentials, returning the user's permissions. Refused requests
+    // have been answered already.
+    fn check_access<'a>(res: Request<'a>, opts: Settings) -> Option<(Request<'a>, Permissions)> {
+        let client_ip = res.peer_addr().map(|addr| addr.ip());
+        let require_auth = match acl::evaluate(&res.path, &res.method) {
+            Some(Action::Deny) => {
+                Self::error(res, opts, "", 403);
+                return None;
+            }
+            Some(Action::Allow) => false,
+            Some(Action::Auth) => true,
+            None => opts.http_auth
+        };
+        
+        let mut perms = Permissions::all();
+        if require_auth && !opts.http_auth {
+            // An ACL rule asks for a user but no credentials are configured
+            Self::error(res, opts, "", 403);
+            return None;
+        } else if require_auth {
+            // API clients authenticate with every request, even when browsers use the login page
+            let uses_login_page = opts.http_auth_login_page && Self::presented_token(&res, opts).is_none();
+            if let Some(retry_after) = client_ip.filter(|_| !uses_login_page).and_then(rate_limit::locked_out) {
+                Self::too_many_requests(res, opts, retry_after);
+                return None;
+            }
+            let authenticated = if uses_login_page {
+                Self::session_user(&res, opts).map(|username| Self::permissions(opts, &username))
+            } else {
+                Self::validate_auth(&res, opts)
+            };
+            let Some(user_perms) = authenticated else {
+                if uses_login_page {
+                    Self::require_login(res, opts);
+                    return None;
+                }
+                // Only count requests that actually tried some credentials, not the initial challenge.
+                // A stale Digest nonce with the right password just needs a fresh nonce.
+                let tried = !res.get_header("authorization").is_empty() || Self::presented_token(&res, opts).is_some();
+                let stale = opts.http_auth_digest && Self::presented_token(&res, opts).is_none() &&
+                    digest::is_stale(&res.get_header("authorization"), &res.method, &res.path, opts.session_secret, |username| Self::digest_password(opts, username));
+                if let Some(ip) = client_ip.filter(|_| tried && !stale) {
+                    rate_limit::record_failure(ip, opts.auth_max_failures, opts.auth_lockout);
+                }
+                Self::error(res, opts, "", 401);
+                return None;
+            };
+            if let Some(ip) = client_ip.filter(|_| !uses_login_page) {
+                rate_limit::record_success(ip);
             }
+            perms = user_perms;
         }
+        if !perms.allows_path(&res.path) {
+            Self::error(res, opts, "", 403);
+            return None;
+        }
+        Some((res, perms))
+    }
+    // Upgrades skip on_request, so WebSockets get the same rate limit, ACL and credential checks here
+    fn allow_upgrade<'a>(res: Request<'a>, opts: Settings) -> Option<Request<'a>> {
+        let res = Self::check_rate_limit(res, opts)?;
+        Self::check_access(res, opts).map(|(res, _)| res)
     }
     fn on_request(mut res:Request, opts: Settings) {
         //todo, this thing
-        println!("Request: {} {}", res.method, res.path);
+        logging::debug(format!("Request: {} {}", res.method, res.path));
         res.set_header("Connection", "keep-alive");
         res.set_header("Accept-ranges", "bytes");
         
@@ -83,8 +285,32 @@ impl SimpleWebServer {
             res.set_header("access-control-max-age", "120");
         }
         
-        if opts.http_auth && !Self::validate_auth(res.get_header("authorization"), opts.http_auth_username, opts.http_auth_password) {
-            Self::error(res, opts, "", 401);
+        // Load balancers poll this often, so it must not count against their rate limit or need credentials
+        if opts.health_check && res.path == HEALTH_PATH {
+            Self::healthz(res);
+            return;
+        }
+        
+        let Some(res) = Self::check_rate_limit(res, opts) else { return; };
+        
+        if opts.http_auth && opts.http_auth_login_page {
+            if res.path == LOGIN_PATH {
+                Self::login(res, opts);
+                return;
+            }
+            if res.path == LOGOUT_PATH {
+                Self::logout(res, opts);
+                return;
+            }
+        }
+        let Some((mut res, perms)) = Self::check_access(res, opts) else { return; };
+        if opts.status_page && res.path == STATUS_PATH {
+            Self::status(res, opts);
+            return;
+        }
+        // The separate metrics port serves them by itself, without credentials
+        if !opts.metrics_path.is_empty() && opts.metrics_port == 0 && res.path == opts.metrics_path {
+            serve_metrics(res, opts);
             return;
         }
         
@@ -94,21 +320,145 @@ impl SimpleWebServer {
         }
         
         if res.method == "GET" || res.method == "HEAD" {
-            Self::get(res, opts, rewrite_to);
+            Self::get(res, opts, rewrite_to, &perms);
         } else if res.method == "PUT" {
-            Self::put(res, opts);
+            Self::put(res, opts, &perms);
         } else if res.method == "DELETE" {
-            Self::delete(res, opts);
+            Self::delete(res, opts, &perms);
         } else if res.method == "OPTIONS" {
             res.end();
         } else {
             Self::error(res, opts, "", 501);
         }
     }
+    fn healthz(mut res:Request) {
+        res.set_header("content-type", "text/plain; charset=utf-8");
+        res.set_header("cache-control", "no-store");
+        res.set_header("Content-length", "3");
+        if res.method != "HEAD" {
+            res.write_string("ok\n");
+        }
+        res.end();
+    }
+    fn status(mut res:Request, opts: Settings) {
+        if res.method != "GET" && res.method != "HEAD" {
+            res.set_header("allow", "GET, HEAD");
+            Self::error(res, opts, "", 405);
+            return;
+        }
+        let json = res.get_query("format").map(|format| format == "json")
+            .unwrap_or_else(|| res.get_header("accept").contains("application/json"));
+        let body = if json { status::render_json(opts) } else { status::render_html(opts) };
+        res.set_header("content-type", if json { "application/json" } else { "text/html; charset=utf-8" });
+        res.set_header("cache-control", "no-store");
+        res.set_header("Content-length", &body.len().to_string());
+        if res.method != "HEAD" {
+            res.write_string(&body);
+        }
+        res.end();
+    }
+    fn session_user(res: &Request, opts: Settings) -> Option<String> {
+        let cookie = res.get_cookie(SESSION_COOKIE)?;
+        session::validate(&cookie, opts.session_secret)
+    }
+    fn require_login(mut res:Request, opts: Settings) {
+        if res.method != "GET" && res.method != "HEAD" {
+            Self::error(res, opts, "", 401);
+            return;
+        }
+        let mut target = res.origpath.clone();
+        if !res.query.is_empty() {
+            target += &format!("?{}", res.query);
+        }
+        res.set_header("location", &format!("{}?redirect={}", LOGIN_PATH, url_encode(&target)));
+        res.set_header("Content-length", "0");
+        res.set_status(303);
+        res.end();
+    }
+    fn login(mut res:Request, opts: Settings) {
+        if res.method == "GET" || res.method == "HEAD" {
+            let redirect = res.get_query("redirect").unwrap_or_default();
+            Self::render_login(res, "", &redirect, 200);
+            return;
+        }
+        if res.method != "POST" {
+            Self::error(res, opts, "", 405);
+            return;
+        }
+        if res.get_header("content-length").parse::<usize>().unwrap_or(0) > MAX_LOGIN_BODY {
+            Self::error(res, opts, "", 413);
+            return;
+        }
+        let client_ip = res.peer_addr().map(|addr| addr.ip());
+        if let Some(retry_after) = client_ip.and_then(rate_limit::locked_out) {
+            Self::too_many_requests(res, opts, retry_after);
+            return;
+        }
+        let form = parse_query(&res.read_all_string());
+        let field = |name: &str| form.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str()).unwrap_or("");
+        let redirect = field("redirect");
+        if !Self::check_credentials(opts, field("username"), field("password")) {
+            if let Some(ip) = client_ip {
+                rate_limit::record_failure(ip, opts.auth_max_failures, opts.auth_lockout);
+            }
+            Self::render_login(res, "Invalid username or password", redirect, 401);
+            return;
+        }
+        if let Some(ip) = client_ip {
+            rate_limit::record_success(ip);
+        }
+        let cookie = session::create(field("username"), opts.session_timeout, opts.session_secret);
+        res.set_cookie(&SetCookie::new(SESSION_COOKIE, &cookie)
+            .path("/")
+            .max_age(opts.session_timeout as i64)
+            .http_only(true)
+            .secure(res.is_https())
+            .same_site(SameSite::Lax));
+        res.set_header("location", safe_redirect(redirect));
+        res.set_header("Content-length", "0");
+        res.set_status(303);
+        res.end();
+    }
+    fn logout(mut res:Request, opts: Settings) {
+        if let Some(cookie) = res.get_cookie(SESSION_COOKIE) {
+            session::destroy(&cookie, opts.session_secret);
+        }
+        res.set_cookie(&SetCookie::removal(SESSION_COOKIE).path("/"));
+        res.set_header("location", LOGIN_PATH);
+        res.set_header("Content-length", "0");
+        res.set_status(303);
+        res.end();
+    }
+    fn render_login(mut res:Request, error: &str, redirect: &str, code: i32) {
+        let page = LOGIN_PAGE
+            .replace("{{action}}", LOGIN_PATH)
+            .replace("{{error}}", &escape_html(error))
+            .replace("{{redirect}}", &escape_html(redirect));
+        res.set_status(code);
+        res.set_header("content-type", "text/html; charset=utf-8");
+        res.set_header("cache-control", "no-store");
+        res.set_header("Content-length", &page.len().to_string());
+        if res.method != "HEAD" {
+            res.write(page.as_bytes());
+        }
+        res.end();
+    }
+    fn too_many_requests(mut res:Request, opts: Settings, retry_after: u64) {
+        res.set_header("Retry-After", &retry_after.to_string());
+        Self::error(res, opts, "", 429);
+    }
     fn error(mut res:Request, opts: Settings, msg: &str, code: i32) {
-        if code == 401 {
+        if code == 401 && opts.http_auth_digest && !opts.http_auth_login_page {
+            let stale = digest::is_stale(&res.get_header("authorization"), &res.method, &res.path, opts.session_secret, |username| Self::digest_password(opts, username));
+            for challenge in digest::challenges(opts.session_secret, stale) {
+                res.append_header("WWW-Authenticate", &challenge);
+            }
+        } else if code == 401 && !opts.http_auth_login_page {
             res.set_header("WWW-Authenticate", "Basic realm=\"SimpleWebServer\", charset=\"UTF-8\"");
         }
+        if code == 401 && !opts.http_auth_tokens_file.is_empty() {
+            res.append_header("WWW-Authenticate", "Bearer realm=\"SimpleWebServer\"");
+        }
         res.set_status(code);
         if ((code == 401 && !opts.custom401.is_empty()) ||
            (code == 403 && !opts.custom403.is_empty()) ||
@@ -123,11 +473,11 @@ impl SimpleWebServer {
                     Self::error(res, opts, if code == 404 { "NONOTUSECUSTOM" } else { "" }, 404);
                     return;
                 }
-                if res.send_file(&entry.path, res.method == "HEAD") == 200 {
+                if res.send_file(&entry.path, res.method == "HEAD").is_ok() {
                     return;
                 }
             } else {
-                Self::log(format!("Failed to read from custom {} path (\"{}\")", code, file_path));
+                logging::warn(format!("Failed to read from custom {} path (\"{}\")", code, file_path));
             }
         }
         res.set_header("content-type", "text/html; charset=utf-8");
@@ -148,13 +498,17 @@ impl SimpleWebServer {
         }
         file_path
     }
-    fn delete(mut res:Request, opts: Settings) {
+    fn delete(mut res:Request, opts: Settings, perms: &Permissions) {
         if !opts.delete {
             res.set_header("Content-length", "0");
             res.set_status(400);
             res.end();
             return;
         }
+        if !perms.delete {
+            Self::error(res, opts, "", 403);
+            return;
+        }
         let file_path = Self::from_relative(opts, res.path.clone());
         let entry = GetByPath::new(&file_path);
         if entry.error || entry.is_directory {
@@ -172,13 +526,21 @@ impl SimpleWebServer {
             }
         }
     }
-    fn put(mut res:Request, opts: Settings) {
+    fn put(mut res:Request, opts: Settings, perms: &Permissions) {
         if !opts.upload {
             Self::error(res, opts, "", 400);
             return;
         }
+        if !perms.upload {
+            Self::error(res, opts, "", 403);
+            return;
+        }
         let file_path = Self::from_relative(opts, res.path.clone());
         let entry = GetByPath::new(&file_path);
+        if !entry.error && opts.replace && !perms.replace {
+            Self::error(res, opts, "", 403);
+            return;
+        }
         if (!entry.error && !opts.replace) || entry.is_directory {
             //file exists
             Self::error(res, opts, "", 400);
@@ -192,15 +554,20 @@ impl SimpleWebServer {
                 }
             }
         }
-        if !res.write_to_file(&file_path) {
-            Self::error(res, opts, "", 500);
+        if let Err(err) = res.write_to_file(&file_path) {
+            logging::error(format!("Failed to write {}: {}", file_path, err));
+            Self::error(res, opts, "", err.status_code());
             return;
         }
         res.set_header("Content-length", "0");
         res.set_status(201);
         res.end();
     }
-    fn get(mut res:Request, opts: Settings, rewrite_to: &str) {
+    fn get(mut res:Request, opts: Settings, rewrite_to: &str, perms: &Permissions) {
+        if !perms.read {
+            Self::error(res, opts, "", 403);
+            return;
+        }
         let path = if rewrite_to.is_empty() { res.path.clone() } else { rewrite_to.to_string() };
         let file_path = Self::from_relative(opts, path);
         let is_head = res.method == "HEAD";
@@ -223,7 +590,7 @@ impl SimpleWebServer {
                     return;
                 }
                 res.set_header("content-type", "text/html; charset=utf-8");
-                if res.send_file(&entry.path, is_head) == 200 {
+                if res.send_file(&entry.path, is_head).is_ok() {
                     return;
                 }
             }
@@ -234,7 +601,7 @@ impl SimpleWebServer {
                     return;
                 }
                 res.set_header("content-type", "text/html; charset=utf-8");
-                if res.send_file(&entry2.path, is_head) == 200 {
+                if res.send_file(&entry2.path, is_head).is_ok() {
                     return;
                 }
             }
@@ -260,28 +627,26 @@ impl SimpleWebServer {
         }
         if opts.index && entry.is_directory {
             if let Ok(paths) = std::fs::read_dir(file_path.clone()) {
-                for path in paths {
-                    if let Ok(path) = path {
-                        let file = path.path().display().to_string();
-                        let name = file.split('/').last().unwrap_or("");
-                        if name == "index.html" || name == "index.htm" {
-                            if entry.is_hidden() && !opts.hidden_dot_files {
-                                Self::error(res, opts, "", 404);
-                                return;
-                            }
-                            res.set_header("content-type", "text/html; charset=utf-8");
-                            if res.send_file(&(file_path.clone()+name), is_head) == 200 {
-                                return;
-                            }
-                        } else if name == "index.xhtml" || name == "index.xhtm" {
-                            if entry.is_hidden() && !opts.hidden_dot_files {
-                                Self::error(res, opts, "", 404);
-                                return;
-                            }
-                            res.set_header("content-type", "application/xhtml+xml; charset=utf-8");
-                            if res.send_file(&(file_path.clone()+name), is_head) == 200 {
-                                return;
-                            }
+                for path in paths.flatten() {
+                    let file = path.path().display().to_string();
+                    let name = file.split('/').next_back().unwrap_or("");
+                    if name == "index.html" || name == "index.htm" {
+                        if entry.is_hidden() && !opts.hidden_dot_files {
+                            Self::error(res, opts, "", 404);
+                            return;
+                        }
+                        res.set_header("content-type", "text/html; charset=utf-8");
+                        if res.send_file(&(file_path.clone()+name), is_head).is_ok() {
+                            return;
+                        }
+                    } else if name == "index.xhtml" || name == "index.xhtm" {
+                        if entry.is_hidden() && !opts.hidden_dot_files {
+                            Self::error(res, opts, "", 404);
+                            return;
+                        }
+                        res.set_header("content-type", "application/xhtml+xml; charset=utf-8");
+                        if res.send_file(&(file_path.clone()+name), is_head).is_ok() {
+                            return;
                         }
                     }
                 }
@@ -289,17 +654,18 @@ impl SimpleWebServer {
         }
         
         
-        let mut rendered = false;
-        if entry.is_hidden() && !opts.hidden_dot_files {
+        let rendered = if entry.is_hidden() && !opts.hidden_dot_files {
             Self::error(res, opts, "", 404);
             return;//rust will complain about a "moved value" so just return.
         } else if entry.is_file {
-            rendered = res.send_file(&entry.path, is_head) == 200;
+            res.send_file(&entry.path, is_head)
         } else if opts.directory_listing && entry.is_directory {
-            rendered = res.directory_listing(&entry.path, is_head, opts.hidden_dot_files_directory_listing) == 200;
-        }
-        if !rendered {
-            Self::error(res, opts, "", 404);
+            res.directory_listing(&entry.path, is_head, opts.hidden_dot_files_directory_listing)
+        } else {
+            Err(Error::NotFound(entry.path.clone()))
+        };
+        if let Err(err) = rendered {
+            Self::error(res, opts, "", err.status_code());
         }
     }
 }
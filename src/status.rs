@@ -0,0 +1,101 @@
+use std::{
+    sync::OnceLock,
+    time::Instant
+};
+use server::{Settings, json_escape, metrics};
+use crate::simple_web_server::escape_html;
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub fn mark_started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+fn uptime() -> u64 {
+    STARTED.get().map(|started| started.elapsed().as_secs()).unwrap_or(0)
+}
+
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, seconds) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, seconds)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else {
+        format!("{}m {}s", minutes, seconds)
+    }
+}
+
+fn auth_mode(opts: Settings) -> &'static str {
+    if !opts.http_auth {
+        "none"
+    } else if opts.http_auth_login_page {
+        "login-page"
+    } else if opts.http_auth_digest {
+        "digest"
+    } else if opts.http_auth_username.is_empty() && opts.http_auth_users_file.is_empty() {
+        "token"
+    } else {
+        "basic"
+    }
+}
+
+fn features(opts: Settings) -> Vec<(&'static str, bool)> {
+    vec![
+        ("upload", opts.upload),
+        ("replace", opts.replace),
+        ("delete", opts.delete),
+        ("https", opts.https || opts.listeners.iter().any(|listener| listener.https)),
+        ("auth", opts.http_auth),
+        ("api_tokens", !opts.http_auth_tokens_file.is_empty()),
+        ("acl", !opts.acl_file.is_empty()),
+        ("directory_listing", opts.directory_listing),
+        ("cors", opts.cors),
+        ("spa", opts.spa),
+        ("rate_limit", opts.rate_limit > 0),
+        ("access_log", !opts.access_log.is_empty()),
+        ("metrics", !opts.metrics_path.is_empty()),
+        ("live_reload", opts.live_reload)
+    ]
+}
+
+pub fn render_json(opts: Settings) -> String {
+    let features = features(opts).iter()
+        .map(|(name, enabled)| format!("{}:{}", json_escape(name), enabled))
+        .collect::<Vec<_>>()
+        .join(",");
+    let classes = metrics::requests_by_status_class();
+    format!("{{\"status\":\"ok\",\"version\":{},\"uptime_seconds\":{},\"root\":{},\"auth\":{},\"features\":{{{}}},\"connections\":{},\"websocket_sessions\":{},\"requests\":{{\"total\":{},\"last_minute\":{},\"last_5_minutes\":{},\"1xx\":{},\"2xx\":{},\"3xx\":{},\"4xx\":{},\"5xx\":{}}},\"bytes_sent\":{}}}",
+        json_escape(env!("CARGO_PKG_VERSION")), uptime(), json_escape(opts.path), json_escape(auth_mode(opts)), features,
+        metrics::connections_in_flight(), metrics::websocket_sessions(),
+        metrics::requests_total(), metrics::recent_requests(60), metrics::recent_requests(300),
+        classes[0], classes[1], classes[2], classes[3], classes[4], metrics::bytes_sent_total())
+}
+
+pub fn render_html(opts: Settings) -> String {
+    let row = |name: &str, value: &str| format!("<tr><th>{}</th><td>{}</td></tr>", escape_html(name), escape_html(value));
+    let mut server = String::new();
+    server += &row("Version", env!("CARGO_PKG_VERSION"));
+    server += &row("Uptime", &format_uptime(uptime()));
+    server += &row("Root", opts.path);
+    server += &row("Authentication", auth_mode(opts));
+    let mut feature_rows = String::new();
+    for (name, enabled) in features(opts) {
+        feature_rows += &row(name, if enabled { "enabled" } else { "disabled" });
+    }
+    let classes = metrics::requests_by_status_class();
+    let mut traffic = String::new();
+    traffic += &row("Open connections", &metrics::connections_in_flight().to_string());
+    traffic += &row("WebSocket sessions", &metrics::websocket_sessions().to_string());
+    traffic += &row("Requests (total)", &metrics::requests_total().to_string());
+    traffic += &row("Requests (last minute)", &metrics::recent_requests(60).to_string());
+    traffic += &row("Requests (last 5 minutes)", &metrics::recent_requests(300).to_string());
+    for (i, count) in classes.iter().enumerate() {
+        traffic += &row(&format!("{}xx responses", i + 1), &count.to_string());
+    }
+    traffic += &row("Bytes sent", &metrics::bytes_sent_total().to_string());
+    format!("<!DOCTYPE html>\n<html lang=\"en\"><head><meta charset=\"utf-8\"><title>Server status</title>\
+        <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse;margin-bottom:1.5em}}th,td{{text-align:left;padding:.25em 1em .25em 0}}th{{font-weight:normal;color:#555}}</style>\
+        </head><body><h1>Server status</h1><h2>Server</h2><table>{}</table><h2>Features</h2><table>{}</table><h2>Traffic</h2><table>{}</table></body></html>",
+        server, feature_rows, traffic)
+}
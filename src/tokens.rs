@@ -0,0 +1,97 @@
+use std::{
+    fs,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+use openssl::{
+    hash::{hash, MessageDigest},
+    memcmp
+};
+use server::date::days_from_civil;
+use crate::users::{Permissions, constant_time_eq};
+
+enum Secret {
+    Plain(String),
+    Sha256(Vec<u8>)
+}
+
+struct Token {
+    secret: Secret,
+    permissions: Permissions,
+    expires: Option<SystemTime>
+}
+
+impl Token {
+    fn matches(&self, token: &str) -> bool {
+        match &self.secret {
+            Secret::Plain(secret) => constant_time_eq(secret, token),
+            Secret::Sha256(digest) => {
+                let Ok(hashed) = hash(MessageDigest::sha256(), token.as_bytes()) else { return false; };
+                digest.len() == hashed.len() && memcmp::eq(digest, &hashed)
+            }
+        }
+    }
+}
+
+static TOKENS: OnceLock<Vec<Token>> = OnceLock::new();
+
+// usize::is_multiple_of needs rustc 1.87, newer than we otherwise require
+#[allow(clippy::manual_is_multiple_of)]
+fn from_hex(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 { return None; };
+    (0..input.len()).step_by(2).map(|i| u8::from_str_radix(input.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Accepts a unix timestamp or a YYYY-MM-DD date (midnight UTC)
+fn parse_expiry(input: &str) -> Option<SystemTime> {
+    if let Ok(secs) = input.parse::<u64>() {
+        return Some(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let parts = input.split('-').collect::<Vec<_>>();
+    if parts.len() != 3 { return None; };
+    let year = parts[0].parse::<i64>().ok()?;
+    let month = parts[1].parse::<u32>().ok().filter(|m| (1..=12).contains(m))?;
+    let day = parts[2].parse::<u32>().ok().filter(|d| (1..=31).contains(d))?;
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400))
+}
+
+// One token per line: `name:token:scopes[:expires]`. The token may be given as `sha256:<hex digest>`
+// instead of in plain text, scopes use the users file rights (read, upload, replace, delete, all)
+// and expires is a unix timestamp or YYYY-MM-DD.
+pub fn load(path: &str) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read tokens file \"{}\": {}", path, e))?;
+    let mut tokens = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; };
+        let invalid = || format!("Invalid entry on line {} of tokens file \"{}\"", i + 1, path);
+        let fields = line.split(':').collect::<Vec<_>>();
+        let (secret, rest) = if fields.len() > 2 && fields[1] == "sha256" {
+            (Secret::Sha256(from_hex(fields[2]).filter(|d| d.len() == 32).ok_or_else(invalid)?), &fields[3..])
+        } else if fields.len() > 1 && !fields[1].is_empty() {
+            (Secret::Plain(fields[1].to_string()), &fields[2..])
+        } else {
+            return Err(invalid());
+        };
+        let expires = match rest.get(1) {
+            Some(expiry) => Some(parse_expiry(expiry).ok_or_else(invalid)?),
+            None => None
+        };
+        tokens.push(Token {
+            secret,
+            permissions: Permissions::parse(rest.first().copied().unwrap_or(""), ""),
+            expires
+        });
+    }
+    TOKENS.set(tokens).map_err(|_| String::from("Tokens file has already been loaded"))
+}
+
+pub fn verify(token: &str) -> Option<Permissions> {
+    if token.is_empty() { return None; };
+    let entry = TOKENS.get()?.iter().find(|entry| entry.matches(token))?;
+    if entry.expires.is_some_and(|expires| expires <= SystemTime::now()) {
+        return None;
+    }
+    Some(entry.permissions.clone())
+}
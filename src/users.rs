@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant}
+};
+use openssl::{
+    hash::{hash, Hasher, MessageDigest},
+    memcmp,
+    sha::sha1
+};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{Engine as _, engine::general_purpose};
+
+static ITOA64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// Successful hash verifications are remembered for a while so bcrypt/argon2 doesn't run on every request
+const VERIFY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct Permissions {
+    pub read: bool,
+    pub upload: bool,
+    pub replace: bool,
+    pub delete: bool,
+    // Path prefixes the user is restricted to. Empty means everywhere
+    pub paths: Vec<String>
+}
+
+impl Permissions {
+    pub fn all() -> Permissions {
+        Permissions {
+            read: true,
+            upload: true,
+            replace: true,
+            delete: true,
+            paths: Vec::new()
+        }
+    }
+    pub fn none() -> Permissions {
+        Permissions {
+            read: false,
+            upload: false,
+            replace: false,
+            delete: false,
+            paths: Vec::new()
+        }
+    }
+    pub fn parse(rights: &str, paths: &str) -> Permissions {
+        let mut perms = Permissions::none();
+        for right in rights.split(',').map(|r| r.trim()) {
+            match right {
+                "read" => perms.read = true,
+                "upload" => perms.upload = true,
+                "replace" => perms.replace = true,
+                "delete" => perms.delete = true,
+                "all" => perms = Permissions::all(),
+                _ => {}
+            }
+        }
+        perms.paths = paths.split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| if p.ends_with('/') { p.to_string() } else { format!("{}/", p) })
+            .collect();
+        perms
+    }
+    pub fn allows_path(&self, path: &str) -> bool {
+        if self.paths.is_empty() { return true; };
+        let dir_path = if path.ends_with('/') { path.to_string() } else { format!("{}/", path) };
+        self.paths.iter().any(|prefix| dir_path.starts_with(prefix.as_str()))
+    }
+}
+
+struct User {
+    hash: String,
+    permissions: Permissions
+}
+
+pub struct Users {
+    users: HashMap<String, User>,
+    verified: Mutex<HashMap<Vec<u8>, Instant>>
+}
+
+static USERS: OnceLock<Users> = OnceLock::new();
+
+// Lines are `user:hash[:rights[:paths]]`, so a plain htpasswd file works as-is (users get read access).
+// rights is a comma separated list of read, upload, replace, delete or all; paths a comma separated list of prefixes.
+pub fn load(path: &str) -> Result<(), String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read users file \"{}\": {}", path, e))?;
+    let mut users = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; };
+        let fields = line.splitn(4, ':').collect::<Vec<_>>();
+        if fields.len() < 2 || fields[0].is_empty() {
+            return Err(format!("Invalid entry on line {} of users file \"{}\"", i + 1, path));
+        }
+        let rights = fields.get(2).copied().unwrap_or("read");
+        let paths = fields.get(3).copied().unwrap_or("");
+        users.insert(fields[0].to_string(), User {
+            hash: fields[1].to_string(),
+            permissions: Permissions::parse(rights, paths)
+        });
+    }
+    USERS.set(Users {
+        users,
+        verified: Mutex::new(HashMap::new())
+    }).map_err(|_| String::from("Users file has already been loaded"))
+}
+
+pub fn permissions(username: &str) -> Permissions {
+    let Some(users) = USERS.get() else { return Permissions::none(); };
+    users.users.get(username).map(|user| user.permissions.clone()).unwrap_or(Permissions::none())
+}
+
+pub fn verify(username: &str, password: &str) -> bool {
+    let Some(users) = USERS.get() else { return false; };
+    let Some(user) = users.users.get(username) else { return false; };
+    let Ok(cache_key) = hash(MessageDigest::sha256(), format!("{}\0{}\0{}", username, password, user.hash).as_bytes()) else { return false; };
+    if let Ok(mut verified) = users.verified.lock() {
+        let now = Instant::now();
+        verified.retain(|_, time| now.duration_since(*time) < VERIFY_CACHE_TTL);
+        if verified.contains_key(cache_key.as_ref()) { return true; };
+    }
+    if !verify_hash(password, &user.hash) { return false; };
+    if let Ok(mut verified) = users.verified.lock() {
+        verified.insert(cache_key.to_vec(), Instant::now());
+    }
+    true
+}
+
+// Compares two strings without leaking where they differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (Ok(a), Ok(b)) = (hash(MessageDigest::sha256(), a.as_bytes()), hash(MessageDigest::sha256(), b.as_bytes())) else { return false; };
+    memcmp::eq(&a, &b)
+}
+
+fn verify_hash(password: &str, stored: &str) -> bool {
+    if stored.starts_with("$2y$") || stored.starts_with("$2a$") || stored.starts_with("$2b$") {
+        bcrypt::verify(password, stored).unwrap_or(false)
+    } else if stored.starts_with("$argon2") {
+        let Ok(parsed) = PasswordHash::new(stored) else { return false; };
+        Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
+    } else if let Some(digest) = stored.strip_prefix("{SHA}") {
+        constant_time_eq(&general_purpose::STANDARD.encode(sha1(password.as_bytes())), digest)
+    } else if let Some(rest) = stored.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        constant_time_eq(&apr1_md5(password, salt), stored)
+    } else {
+        false
+    }
+}
+
+fn to64(out: &mut String, mut value: u32, count: usize) {
+    for _ in 0..count {
+        out.push(ITOA64[(value & 0x3f) as usize] as char);
+        value >>= 6;
+    }
+}
+
+fn md5(parts: &[&[u8]]) -> Vec<u8> {
+    let Ok(mut hasher) = Hasher::new(MessageDigest::md5()) else { return Vec::new(); };
+    for part in parts {
+        if hasher.update(part).is_err() { return Vec::new(); };
+    }
+    hasher.finish().map(|digest| digest.to_vec()).unwrap_or_default()
+}
+
+// Apache's MD5-crypt variant, the default format of `htpasswd`
+fn apr1_md5(password: &str, salt: &str) -> String {
+    let pw = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+    let alt = md5(&[pw, salt, pw]);
+    if alt.len() != 16 { return String::new(); };
+
+    let mut ctx = vec![pw.to_vec(), b"$apr1$".to_vec(), salt.to_vec()];
+    let mut remaining = pw.len();
+    while remaining > 0 {
+        let len = remaining.min(16);
+        ctx.push(alt[..len].to_vec());
+        remaining -= len;
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        ctx.push(if i & 1 == 1 { vec![0] } else { pw[..1].to_vec() });
+        i >>= 1;
+    }
+    let mut digest = md5(&ctx.iter().map(|part| part.as_slice()).collect::<Vec<_>>());
+
+    for round in 0..1000 {
+        let mut parts: Vec<&[u8]> = Vec::new();
+        parts.push(if round & 1 == 1 { pw } else { &digest });
+        if round % 3 != 0 { parts.push(salt); };
+        if round % 7 != 0 { parts.push(pw); };
+        parts.push(if round & 1 == 1 { &digest } else { pw });
+        digest = md5(&parts);
+        if digest.len() != 16 { return String::new(); };
+    }
+
+    let mut out = format!("$apr1${}$", String::from_utf8_lossy(salt));
+    let d = |i: usize| digest[i] as u32;
+    to64(&mut out, (d(0) << 16) | (d(6) << 8) | d(12), 4);
+    to64(&mut out, (d(1) << 16) | (d(7) << 8) | d(13), 4);
+    to64(&mut out, (d(2) << 16) | (d(8) << 8) | d(14), 4);
+    to64(&mut out, (d(3) << 16) | (d(9) << 8) | d(15), 4);
+    to64(&mut out, (d(4) << 16) | (d(10) << 8) | d(5), 4);
+    to64(&mut out, d(11), 2);
+    out
+}